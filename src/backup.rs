@@ -0,0 +1,630 @@
+//! Storing a directory tree as a snapshot
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read,Seek,SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt,MetadataExt};
+use std::path::Path;
+
+use cache::{FileCache,CacheEntry};
+use exclude::Exclude;
+use restore::lookup;
+use snapshot::{Snapshot,hostname};
+use tree::{Tree,TreeEntry,EntryKind,Time,Xattr,XattrValue,Inode};
+use libc;
+use {Store,Oid,BlobWriter,sys};
+
+/// Extended attribute values longer than this are stored as blobs
+const XATTR_INLINE_MAX: usize = 1024;
+
+/// Name of the file marking a directory as a cache, see <https://bford.info/cachedir/>
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Selects extended attributes by namespace (the part of the name before the first `.`, like
+/// `user` or `security`)
+#[derive(Debug,Clone,Default)]
+pub struct XattrFilter {
+    /// If not empty, only these namespaces are included
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl XattrFilter {
+    pub fn matches(&self, name: &[u8]) -> bool {
+        let ns = name.split(|&b| b == b'.').next().unwrap_or(&[]);
+        let has = |l: &[String]| l.iter().any(|n| n.as_bytes() == ns);
+        (self.include.is_empty() || has(&self.include)) && !has(&self.exclude)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct BackupOptions {
+    /// Recorded in the snapshot
+    pub tags: Vec<String>,
+    /// Host name recorded in the snapshot, defaults to the name of this machine
+    pub host: Option<String>,
+    /// Record the extended attributes selected by the filter. `None` records none.
+    pub xattrs: Option<XattrFilter>,
+    /// Read every file, even those the metadata cache says are unchanged since the last backup
+    /// of the same source
+    pub force_rehash: bool,
+    /// A snapshot (or tree) of an earlier backup of the same source. Files whose size,
+    /// modification time & inode match the entry at the same path in it are not read.
+    pub parent: Option<Oid>,
+    /// Rules for entries not to back up, as lines of a `.gitignore` file (see `Exclude`)
+    pub exclude: Vec<String>,
+    /// Don't descend into directories on other filesystems than the source. The directories
+    /// themselves are recorded, empty.
+    pub one_file_system: bool,
+    /// Leave out the content of directories containing a valid `CACHEDIR.TAG`, other than the
+    /// tag itself
+    pub exclude_caches: bool,
+    /// Leave out files longer than this
+    pub max_file_size: Option<u64>,
+    /// Times to read a file again if it changed while being read
+    pub retries: u32,
+    /// Fail the backup if a file is still changing after `retries`, rather than recording it as
+    /// inconsistent in the snapshot
+    pub fail_on_change: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            tags: vec![],
+            host: None,
+            xattrs: None,
+            force_rehash: false,
+            parent: None,
+            exclude: vec![],
+            one_file_system: false,
+            exclude_caches: false,
+            max_file_size: None,
+            retries: 2,
+            fail_on_change: false,
+        }
+    }
+}
+
+/// What a backup did
+#[derive(Debug,Clone)]
+pub struct BackupSummary {
+    pub snapshot: Oid,
+    pub tree: Oid,
+    pub files: u64,
+    /// Files whose content was already stored for another hard link to them
+    pub hardlinks: u64,
+    /// Files whose content was reused from the metadata cache or the parent snapshot without
+    /// reading them
+    pub cached: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Devices & fifos
+    pub special: u64,
+    /// Entries that could not be stored (sockets)
+    pub skipped: u64,
+    /// Entries left out by the exclude rules, cache tags or size limit
+    pub excluded: u64,
+    /// Files that changed while being read (even after retrying), relative to the source
+    pub inconsistent: Vec<String>,
+    /// Bytes of file content read, not counting holes in sparse files
+    pub bytes_read: u64,
+    /// Bytes of objects written that were not already in the store
+    pub bytes_stored: u64,
+}
+
+/// Copy `f` to `w`, recording its holes as holes. Returns the length of the file & the number of
+/// bytes of data read.
+fn copy_sparse(f: &mut File, w: &mut BlobWriter) -> io::Result<(u64, u64)> {
+    let mut pos = 0;
+    let mut read = 0;
+    loop {
+        let data = match sys::seek_data(f, pos) {
+            Ok(v) => v,
+            Err(ref e) if pos == 0 && (e.raw_os_error() == Some(libc::EINVAL) ||
+                                       e.raw_os_error() == Some(libc::ENOTSUP)) => {
+                // no hole reporting, so read the whole thing
+                f.seek(SeekFrom::Start(0))?;
+                let n = io::copy(f, w)?;
+                return Ok((n, n));
+            },
+            Err(e) => return Err(e),
+        };
+        let data = match data {
+            Some(d) => d,
+            None => {
+                let end = f.seek(SeekFrom::End(0))?;
+                if end > pos {
+                    w.write_hole(end - pos)?;
+                    pos = end;
+                }
+                return Ok((pos, read));
+            },
+        };
+
+        w.write_hole(data - pos)?;
+        let hole = sys::seek_hole(f, data)?;
+        f.seek(SeekFrom::Start(data))?;
+        let n = io::copy(&mut (&mut *f).take(hole - data), w)?;
+        read += n;
+        pos = data + n;
+        if n < hole - data {
+            // truncated while we were reading it
+            return Ok((pos, read));
+        }
+    }
+}
+
+/// Include the path in errors, as they would otherwise be meaningless on a large walk
+fn at(p: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", p.display(), e))
+}
+
+struct Walk<'a> {
+    store: &'a Store,
+    opts: &'a BackupOptions,
+    files: u64,
+    hardlinks: u64,
+    cached: u64,
+    dirs: u64,
+    symlinks: u64,
+    special: u64,
+    skipped: u64,
+    excluded: u64,
+    inconsistent: Vec<String>,
+    bytes_read: u64,
+    /// Length of the files read, including holes
+    size: u64,
+
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+    /// Content of files with more than one link, by (device, inode)
+    linked: HashMap<(u64, u64), (Oid, u64)>,
+
+    source: &'a Path,
+    /// device of the source, for `one_file_system`
+    dev: u64,
+    exclude: Exclude,
+    /// when the backup started, in seconds
+    time: i64,
+    /// from the last backup of this source
+    old_cache: FileCache,
+    cache: FileCache,
+}
+
+impl<'a> Walk<'a> {
+    /// Record the POSIX metadata of `m` in `e`
+    fn meta(&mut self, e: &mut TreeEntry, m: &::std::fs::Metadata) {
+        e.mode = Some(m.mode() & 0o7777);
+        e.uid = Some(m.uid());
+        e.gid = Some(m.gid());
+        e.user = self.users.entry(m.uid()).or_insert_with(|| sys::user_name(m.uid())).clone();
+        e.group = self.groups.entry(m.gid()).or_insert_with(|| sys::group_name(m.gid())).clone();
+        e.mtime = Some(Time { secs: m.mtime(), nsecs: m.mtime_nsec() as u32 });
+        e.ctime = Some(Time { secs: m.ctime(), nsecs: m.ctime_nsec() as u32 });
+        // only needed to find hard links, & recording it otherwise would make trees differ
+        // needlessly (say between a directory & a copy of it)
+        if !m.is_dir() && m.nlink() > 1 {
+            e.inode = Some(Inode { dev: m.dev(), ino: m.ino(), nlink: m.nlink() });
+        }
+    }
+
+    fn xattrs(&mut self, e: &mut TreeEntry, p: &Path) -> io::Result<()> {
+        let filter = match self.opts.xattrs {
+            Some(ref f) => f,
+            None => return Ok(()),
+        };
+
+        let f = sys::XattrFile::open(p, e.kind)?;
+        let mut names = f.list()?;
+        names.sort();
+        for name in names {
+            if !filter.matches(&name) {
+                continue;
+            }
+            let v = match f.get(&name)? {
+                Some(v) => v,
+                None => continue,
+            };
+            let value = if v.len() > XATTR_INLINE_MAX {
+                XattrValue::Blob(self.store.put_blob(&v)?)
+            } else {
+                XattrValue::Inline(v)
+            };
+            e.xattrs.push(Xattr { name: name, value: value });
+        }
+        Ok(())
+    }
+
+    /// The oid of the content of `prev`, an entry from the parent snapshot, if it can be used
+    /// for the file with metadata `m`.
+    ///
+    /// The device number is not compared, as it can change between boots. The inode number is
+    /// only recorded for files with more than one link, but a file replaced by another has a new
+    /// change time.
+    fn from_parent(&self, prev: Option<&TreeEntry>, m: &::std::fs::Metadata) -> io::Result<Option<Oid>> {
+        let prev = match prev {
+            Some(e) if e.kind == EntryKind::File && !self.opts.force_rehash => e,
+            _ => return Ok(None),
+        };
+        let ino = if m.nlink() > 1 { Some(m.ino()) } else { None };
+        let same = prev.size == m.len() &&
+            prev.mtime == Some(Time { secs: m.mtime(), nsecs: m.mtime_nsec() as u32 }) &&
+            prev.ctime == Some(Time { secs: m.ctime(), nsecs: m.ctime_nsec() as u32 }) &&
+            prev.inode.map(|i| i.ino) == ino;
+        match prev.oid {
+            Some(ref oid) if same && self.store.contains(oid)? => Ok(Some(oid.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the file `p` into a blob, retrying if it changes while being read (see
+    /// `read_consistently`).
+    ///
+    /// `m` is updated to the metadata the file had after it was last read. Returns the oid &
+    /// length of the blob, and whether the file stayed unchanged while it was read.
+    fn read_file(&mut self, p: &Path, m: &mut ::std::fs::Metadata) -> io::Result<(Oid, u64, bool)> {
+        let opts = self.opts;
+        let ((oid, len), same) = read_consistently(opts, || {
+            let mut f = File::open(p)?;
+            let before = f.metadata()?;
+            let mut w = self.store.blob_writer();
+            let (len, read) = copy_sparse(&mut f, &mut w)?;
+            self.bytes_read += read;
+            let oid = w.commit()?;
+            let after = f.metadata()?;
+
+            let same = unchanged(&before, &after) && len == after.len();
+            *m = after;
+            Ok(((oid, len), same))
+        })?;
+        Ok((oid, len, same))
+    }
+
+    fn file(&mut self, p: &Path, m: &mut ::std::fs::Metadata, prev: Option<&TreeEntry>) -> io::Result<(Oid, u64)> {
+        if m.nlink() > 1 {
+            if let Some(v) = self.linked.get(&(m.dev(), m.ino())) {
+                self.files += 1;
+                self.hardlinks += 1;
+                return Ok(v.clone());
+            }
+        }
+
+        let rel = p.strip_prefix(self.source).unwrap_or(p).as_os_str().as_bytes().to_owned();
+        let cached = match self.old_cache.get(&rel) {
+            Some(c) if !self.opts.force_rehash && c.matches(m) => {
+                // it may have been removed from the store since
+                if self.store.contains(&c.oid)? { Some(c.oid.clone()) } else { None }
+            },
+            _ => None,
+        };
+        let cached = match cached {
+            Some(oid) => Some(oid),
+            None => self.from_parent(prev, m)?,
+        };
+
+        let (oid, len, consistent) = match cached {
+            Some(oid) => {
+                self.cached += 1;
+                (oid, m.len(), true)
+            },
+            None => self.read_file(p, m)?,
+        };
+        self.files += 1;
+        self.size += len;
+
+        if !consistent {
+            self.inconsistent.push(String::from_utf8_lossy(&rel).into_owned());
+        }
+
+        // A file changed within the same second as we read it could change again without its
+        // times changing, so only cache files that have been left alone for a while
+        if consistent && m.mtime() < self.time && m.ctime() < self.time {
+            self.cache.insert(rel, CacheEntry::new(m, oid.clone()));
+        }
+        if m.nlink() > 1 {
+            self.linked.insert((m.dev(), m.ino()), (oid.clone(), len));
+        }
+        Ok((oid, len))
+    }
+
+    /// Store the directory `p` & everything below it, returning the oid of its tree. `parent` is
+    /// the oid & tree of the same directory in the parent snapshot.
+    fn dir(&mut self, p: &Path, parent: Option<&(Oid, Tree)>) -> io::Result<Oid> {
+        let mut tree = Tree::new();
+        let mut names = vec![];
+        for e in ::std::fs::read_dir(p).map_err(|e| at(p, e))? {
+            names.push(e.map_err(|e| at(p, e))?.file_name());
+        }
+        // walk in a fixed order, so the same hard link is always the one read
+        names.sort();
+
+        if self.opts.exclude_caches && is_cachedir(p).map_err(|e| at(p, e))? {
+            let before = names.len();
+            names.retain(|n| n.as_bytes() == CACHEDIR_TAG.as_bytes());
+            self.excluded += (before - names.len()) as u64;
+        }
+
+        for n in names {
+            let ep = p.join(&n);
+            let mut m = ::std::fs::symlink_metadata(&ep).map_err(|e| at(&ep, e))?;
+            let ft = m.file_type();
+            let name = n.as_bytes().to_owned();
+            let rel = ep.strip_prefix(self.source).unwrap_or(&ep).as_os_str().as_bytes().to_owned();
+            let too_big = match self.opts.max_file_size {
+                Some(max) => ft.is_file() && m.len() > max,
+                None => false,
+            };
+            if too_big || self.exclude.is_excluded(&rel, ft.is_dir()) {
+                self.excluded += 1;
+                continue;
+            }
+            let prev = parent.and_then(|&(_, ref t)| t.get(&name));
+
+            let mut entry = if ft.is_file() {
+                let (oid, len) = self.file(&ep, &mut m, prev).map_err(|e| at(&ep, e))?;
+                let mut te = TreeEntry::new(name, EntryKind::File);
+                te.oid = Some(oid);
+                te.size = len;
+                te
+            } else if ft.is_dir() {
+                let sub = match prev {
+                    Some(&TreeEntry { kind: EntryKind::Dir, oid: Some(ref oid), .. }) =>
+                        self.store.get_tree(oid)?.map(|t| (oid.clone(), t)),
+                    _ => None,
+                };
+                let mut te = TreeEntry::new(name, EntryKind::Dir);
+                te.oid = Some(if self.opts.one_file_system && m.dev() != self.dev {
+                    // a mount point
+                    self.dirs += 1;
+                    self.store.put_tree(&Tree::new())?
+                } else {
+                    self.dir(&ep, sub.as_ref())?
+                });
+                te
+            } else if ft.is_symlink() {
+                let t = ::std::fs::read_link(&ep).map_err(|e| at(&ep, e))?;
+                let mut te = TreeEntry::new(name, EntryKind::Symlink);
+                te.target = Some(t.as_os_str().as_bytes().to_owned());
+                self.symlinks += 1;
+                te
+            } else if ft.is_block_device() || ft.is_char_device() || ft.is_fifo() {
+                let kind = if ft.is_block_device() {
+                    EntryKind::BlockDevice
+                } else if ft.is_char_device() {
+                    EntryKind::CharDevice
+                } else {
+                    EntryKind::Fifo
+                };
+                let mut te = TreeEntry::new(name, kind);
+                if kind != EntryKind::Fifo {
+                    te.rdev = Some(m.rdev());
+                }
+                self.special += 1;
+                te
+            } else {
+                self.skipped += 1;
+                continue;
+            };
+
+            self.meta(&mut entry, &m);
+            self.xattrs(&mut entry, &ep).map_err(|e| at(&ep, e))?;
+            tree.entries.push(entry);
+        }
+
+        tree.sort();
+        self.dirs += 1;
+        if let Some(&(ref oid, ref t)) = parent {
+            if *t == tree {
+                // nothing changed below here
+                return Ok(oid.clone());
+            }
+        }
+        self.store.put_tree(&tree)
+    }
+}
+
+/// Call `read`, which reads a file once & says whether it stayed unchanged meanwhile, until the
+/// file stays unchanged or `opts.retries` is used up. Returns the last result & whether the file
+/// stayed unchanged, or an error if it did not & `opts.fail_on_change` is set.
+fn read_consistently<T, F>(opts: &BackupOptions, mut read: F) -> io::Result<(T, bool)>
+    where F: FnMut() -> io::Result<(T, bool)>
+{
+    let mut tries = 0;
+    loop {
+        let (v, same) = read()?;
+        if same {
+            return Ok((v, true));
+        }
+        if tries >= opts.retries {
+            if opts.fail_on_change {
+                return Err(io::Error::new(io::ErrorKind::Other, "changed while being read"));
+            }
+            return Ok((v, false));
+        }
+        tries += 1;
+    }
+}
+
+/// Whether the two stats of a file show no sign of it having changed in between
+fn unchanged(a: &::std::fs::Metadata, b: &::std::fs::Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino() && a.len() == b.len() &&
+        (a.mtime(), a.mtime_nsec()) == (b.mtime(), b.mtime_nsec()) &&
+        (a.ctime(), a.ctime_nsec()) == (b.ctime(), b.ctime_nsec())
+}
+
+/// Whether the directory `p` contains a valid cache directory tag
+fn is_cachedir(p: &Path) -> io::Result<bool> {
+    let mut f = match File::open(p.join(CACHEDIR_TAG)) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut b = vec![];
+    f.by_ref().take(CACHEDIR_SIGNATURE.len() as u64).read_to_end(&mut b)?;
+    Ok(b == CACHEDIR_SIGNATURE)
+}
+
+pub(crate) fn backup(store: &Store, source: &Path, opts: &BackupOptions) -> io::Result<BackupSummary> {
+    let source = source.canonicalize().map_err(|e| at(source, e))?;
+    let sm = ::std::fs::metadata(&source).map_err(|e| at(&source, e))?;
+    if !sm.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("{} is not a directory", source.display())));
+    }
+
+    // held until the snapshot is written, so gc can't remove objects this backup reuses (from the
+    // cache or parent) or has written but not yet referred to
+    let _lock = store.lock(false)?;
+    let time = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs()).unwrap_or(0);
+    let stored_start = store.stored_bytes();
+    let old_cache = FileCache::load(store, &source)?;
+    let parent = match opts.parent {
+        Some(ref oid) => {
+            let t = lookup(store, oid, "")?.oid.unwrap();
+            let tree = store.get_tree(&t)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("missing tree {}", t)))?;
+            Some((t, tree))
+        },
+        None => None,
+    };
+
+    let mut w = Walk {
+        store: store,
+        opts: opts,
+        files: 0,
+        hardlinks: 0,
+        cached: 0,
+        dirs: 0,
+        symlinks: 0,
+        special: 0,
+        skipped: 0,
+        excluded: 0,
+        inconsistent: vec![],
+        bytes_read: 0,
+        size: 0,
+        users: HashMap::new(),
+        groups: HashMap::new(),
+        linked: HashMap::new(),
+        source: &source,
+        dev: sm.dev(),
+        exclude: Exclude::new(&opts.exclude),
+        time: time as i64,
+        old_cache: old_cache,
+        cache: FileCache::default(),
+    };
+    let tree = w.dir(&source, parent.as_ref())?;
+    let mut root = TreeEntry::new("", EntryKind::Dir);
+    root.oid = Some(tree.clone());
+    w.meta(&mut root, &sm);
+    w.xattrs(&mut root, &source).map_err(|e| at(&source, e))?;
+
+    let mut s = Snapshot::new(tree.clone());
+    s.time = time;
+    s.host = opts.host.clone().unwrap_or_else(hostname);
+    s.source = source.to_string_lossy().into_owned();
+    s.root = Some(root);
+    s.tags = opts.tags.clone();
+    s.files = w.files;
+    s.size = w.size;
+    s.inconsistent = w.inconsistent.clone();
+    let oid = store.put_snapshot(&s)?;
+    store.snapshots()?.add(&oid)?;
+    // files no longer in the source are dropped from the cache
+    w.cache.save(store, &source)?;
+
+    Ok(BackupSummary {
+        snapshot: oid,
+        tree: tree,
+        files: w.files,
+        hardlinks: w.hardlinks,
+        cached: w.cached,
+        dirs: w.dirs,
+        symlinks: w.symlinks,
+        special: w.special,
+        skipped: w.skipped,
+        excluded: w.excluded,
+        inconsistent: w.inconsistent,
+        bytes_read: w.bytes_read,
+        bytes_stored: store.stored_bytes() - stored_start,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use super::{BackupOptions,XattrFilter,read_consistently,unchanged};
+
+    #[test]
+    fn unchanged_metadata() {
+        let d = ::tempdir::TempDir::new(module_path!()).unwrap();
+        let p = d.path().join("f");
+        fs::write(&p, b"a").unwrap();
+        let a = fs::metadata(&p).unwrap();
+        assert!(unchanged(&a, &fs::metadata(&p).unwrap()));
+
+        // grown, even if the times are too coarse to show it
+        fs::OpenOptions::new().append(true).open(&p).unwrap().write_all(b"b").unwrap();
+        assert!(!unchanged(&a, &fs::metadata(&p).unwrap()));
+
+        // replaced by another file
+        fs::write(d.path().join("g"), b"a").unwrap();
+        fs::rename(d.path().join("g"), &p).unwrap();
+        let b = fs::metadata(&p).unwrap();
+        assert!(a.ino() != b.ino());
+        assert!(!unchanged(&a, &b));
+    }
+
+    /// Reads that see the file change `changes` times, then not. Returns the result & the
+    /// number of reads.
+    fn reads(opts: &BackupOptions, changes: u32) -> (::std::io::Result<(u32, bool)>, u32) {
+        let mut n = 0;
+        let r = read_consistently(opts, || {
+            n += 1;
+            Ok((n, n > changes))
+        });
+        (r, n)
+    }
+
+    #[test]
+    fn retries() {
+        let opts = BackupOptions::default();
+        assert_eq!(opts.retries, 2);
+        let (r, n) = reads(&opts, 0);
+        assert_eq!((r.unwrap(), n), ((1, true), 1));
+        let (r, n) = reads(&opts, 2);
+        assert_eq!((r.unwrap(), n), ((3, true), 3));
+
+        // gives up, keeping the last read
+        let (r, n) = reads(&opts, 3);
+        assert_eq!((r.unwrap(), n), ((3, false), 3));
+        let none = BackupOptions { retries: 0, ..BackupOptions::default() };
+        let (r, n) = reads(&none, 1);
+        assert_eq!((r.unwrap(), n), ((1, false), 1));
+
+        let fail = BackupOptions { fail_on_change: true, ..BackupOptions::default() };
+        let (r, n) = reads(&fail, 3);
+        assert!(r.is_err());
+        assert_eq!(n, 3);
+        let (r, _) = reads(&fail, 2);
+        assert_eq!(r.unwrap(), (3, true));
+    }
+
+    #[test]
+    fn xattr_filter() {
+        let all = XattrFilter::default();
+        assert!(all.matches(b"user.x"));
+        assert!(all.matches(b"security.capability"));
+
+        let f = XattrFilter { include: vec!["user".to_owned(), "system".to_owned()], exclude: vec!["system".to_owned()] };
+        assert!(f.matches(b"user.x"));
+        assert!(!f.matches(b"username.x"));
+        assert!(!f.matches(b"system.posix_acl_access"));
+        assert!(!f.matches(b"security.selinux"));
+    }
+}
@@ -0,0 +1,647 @@
+//! Measure the behaviour of content defined chunking on some input data
+//!
+//! Each input stream (a file, stdin, or generated random data) is split independently, just as
+//! `Store::put_blob` splits each blob independently. Pieces are hashed to find duplicates across
+//! all of the streams, which gives the deduplication a store would see for the same data.
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::path::{Path,PathBuf};
+use std::time::{Duration,Instant};
+use rand::{Rng,SeedableRng,XorShiftRng};
+
+/// Where data for a benchmark comes from
+#[derive(Clone)]
+pub enum Input {
+    /// `bytes` of pseudo random data
+    Random { bytes: u64 },
+    File(PathBuf),
+    /// All regular files below a directory. Symlinks are not followed.
+    Dir(PathBuf),
+    Stdin,
+    /// Data already read into memory, used when stdin needs to be split more than once
+    Memory(Vec<u8>),
+    /// Each stream of `base` with `Mutations` applied
+    Mutated(Box<Input>, Mutations),
+}
+
+/// A kind of edit that `Mutations` may make
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Edit {
+    /// Insert 1 to 16 random bytes
+    Insert,
+    /// Remove 1 to 16 bytes
+    Delete,
+    /// Change the value of a single byte
+    Flip,
+}
+
+impl Edit {
+    pub fn from_name(n: &str) -> Option<Self> {
+        match n {
+            "insert" => Some(Edit::Insert),
+            "delete" => Some(Edit::Delete),
+            "flip" => Some(Edit::Flip),
+            _ => None,
+        }
+    }
+}
+
+/// Randomly placed edits, used to synthesize a new version of some data
+#[derive(Debug,Clone)]
+pub struct Mutations {
+    /// number of edits made to each stream
+    pub count: usize,
+    /// kinds of edits to choose from
+    pub edits: Vec<Edit>,
+    pub seed: u32,
+}
+
+impl Mutations {
+    /// Apply the edits to `data`. `index` identifies the stream, so that different streams of
+    /// an input get different (but still reproducible) edits.
+    pub fn apply(&self, data: &[u8], index: u32) -> Vec<u8> {
+        let mut rng = XorShiftRng::from_seed([self.seed, index, 0x6d75_7461, 0x7465_0001]);
+        let mut d = data.to_owned();
+        if self.edits.is_empty() {
+            return d;
+        }
+
+        for _ in 0..self.count {
+            let at = rng.gen_range(0, d.len() + 1);
+            let len = rng.gen_range(1, 17);
+            match *rng.choose(&self.edits).unwrap() {
+                Edit::Insert => {
+                    let ins: Vec<u8> = rng.gen_iter::<u8>().take(len).collect();
+                    let tail = d.split_off(at);
+                    d.extend(ins);
+                    d.extend(tail);
+                },
+                Edit::Delete => {
+                    let end = ::std::cmp::min(at + len, d.len());
+                    d.drain(at..end);
+                },
+                Edit::Flip => {
+                    if at < d.len() {
+                        d[at] ^= rng.gen_range(1, 256) as u8;
+                    }
+                },
+            }
+        }
+        d
+    }
+}
+
+/// Generates a deterministic stream of pseudo random bytes
+struct RandomReader {
+    rng: XorShiftRng,
+    remain: u64,
+}
+
+impl RandomReader {
+    fn new(bytes: u64) -> Self {
+        RandomReader {
+            rng: XorShiftRng::from_seed([0x7662_6c6f, 0x636b_2062, 0x656e_6368, 1]),
+            remain: bytes,
+        }
+    }
+}
+
+impl Read for RandomReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let l = ::std::cmp::min(buf.len() as u64, self.remain) as usize;
+        self.rng.fill_bytes(&mut buf[..l]);
+        self.remain -= l as u64;
+        Ok(l)
+    }
+}
+
+/// Regular files below `p`, in a stable order
+fn walk(p: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut ents = vec![];
+    for e in ::std::fs::read_dir(p)? {
+        ents.push(e?.path());
+    }
+    ents.sort();
+
+    for e in ents {
+        let m = ::std::fs::symlink_metadata(&e)?;
+        if m.is_dir() {
+            walk(&e, out)?;
+        } else if m.is_file() {
+            out.push(e);
+        }
+    }
+    Ok(())
+}
+
+impl Input {
+    /// Call `f` with each stream making up the input
+    pub fn for_each(&self, f: &mut dyn FnMut(&mut dyn Read) -> io::Result<()>) -> io::Result<()> {
+        match *self {
+            Input::Random { bytes } => f(&mut RandomReader::new(bytes)),
+            Input::File(ref p) => f(&mut ::std::fs::File::open(p)?),
+            Input::Dir(ref p) => {
+                let mut files = vec![];
+                walk(p, &mut files)?;
+                for p in files {
+                    f(&mut ::std::fs::File::open(&p)?)?;
+                }
+                Ok(())
+            },
+            Input::Stdin => {
+                let i = io::stdin();
+                let mut l = i.lock();
+                f(&mut l)
+            },
+            Input::Memory(ref d) => f(&mut io::Cursor::new(&d[..])),
+            Input::Mutated(ref base, ref m) => {
+                let mut index = 0;
+                base.for_each(&mut |r: &mut dyn Read| {
+                    let mut d = vec![];
+                    r.read_to_end(&mut d)?;
+                    let d = m.apply(&d, index);
+                    index += 1;
+                    f(&mut io::Cursor::new(&d[..]))
+                })
+            },
+        }
+    }
+
+    /// Convert inputs that can only be read once into ones that can be read repeatedly
+    pub fn make_repeatable(self) -> io::Result<Self> {
+        match self {
+            Input::Stdin => {
+                let mut d = vec![];
+                io::stdin().read_to_end(&mut d)?;
+                Ok(Input::Memory(d))
+            },
+            i => Ok(i),
+        }
+    }
+}
+
+/// Number of power-of-2 buckets in the piece size histogram
+const HIST_BUCKETS: usize = 48;
+
+/// Results of splitting some data
+pub struct Stats {
+    pub bytes: u64,
+    pub pieces: u64,
+    pub unique_pieces: u64,
+    pub unique_bytes: u64,
+    /// Distinct pieces that were also in the data split before the last `Bench::take_stats`
+    pub shared_pieces: u64,
+    pub shared_bytes: u64,
+    pub min_piece: u64,
+    pub max_piece: u64,
+    size_sum_sq: f64,
+
+    /// `hist[i]` is the number of pieces with a size in `[2^i, 2^(i+1))`
+    pub hist: Vec<u64>,
+
+    /// Time spent in the splitter alone
+    pub split_time: Duration,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            bytes: 0,
+            pieces: 0,
+            unique_pieces: 0,
+            unique_bytes: 0,
+            shared_pieces: 0,
+            shared_bytes: 0,
+            min_piece: 0,
+            max_piece: 0,
+            size_sum_sq: 0.0,
+            hist: vec![0;HIST_BUCKETS],
+            split_time: Duration::new(0, 0),
+        }
+    }
+
+    pub fn avg_piece(&self) -> f64 {
+        if self.pieces == 0 {
+            0.0
+        } else {
+            self.bytes as f64 / self.pieces as f64
+        }
+    }
+
+    pub fn stddev_piece(&self) -> f64 {
+        if self.pieces == 0 {
+            return 0.0;
+        }
+        let avg = self.avg_piece();
+        (self.size_sum_sq / self.pieces as f64 - avg * avg).max(0.0).sqrt()
+    }
+
+    /// Fraction of the input that needs to be stored after deduplication
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            1.0
+        } else {
+            self.unique_bytes as f64 / self.bytes as f64
+        }
+    }
+
+    /// Splitting speed in bytes per second
+    pub fn throughput(&self) -> f64 {
+        let t = ::secs(self.split_time);
+        if t == 0.0 {
+            0.0
+        } else {
+            self.bytes as f64 / t
+        }
+    }
+}
+
+/// Splits data & accumulates `Stats`
+pub struct Bench {
+    kind: ::vblock::ChunkerKind,
+    size: ::vblock::PieceSize,
+    hash: ::vblock::HashAlg,
+    /// Pieces seen since the last `take_stats`
+    seen: HashSet<::vblock::Oid>,
+    /// Pieces seen before it
+    before: HashSet<::vblock::Oid>,
+    stats: Stats,
+}
+
+impl Bench {
+    pub fn new(kind: ::vblock::ChunkerKind, size: ::vblock::PieceSize) -> Self {
+        Bench {
+            kind: kind,
+            size: size,
+            hash: ::vblock::HashAlg::Blake3,
+            seen: HashSet::new(),
+            before: HashSet::new(),
+            stats: Stats::new(),
+        }
+    }
+
+    fn piece(&mut self, p: &[u8]) {
+        let l = p.len() as u64;
+        let s = &mut self.stats;
+        if s.pieces == 0 || l < s.min_piece {
+            s.min_piece = l;
+        }
+        if l > s.max_piece {
+            s.max_piece = l;
+        }
+        s.pieces += 1;
+        s.size_sum_sq += (l as f64) * (l as f64);
+        let b = 64 - l.leading_zeros() as usize;
+        s.hist[b.saturating_sub(1)] += 1;
+
+        let oid = self.hash.oid(p);
+        if self.before.contains(&oid) {
+            if self.seen.insert(oid) {
+                s.shared_pieces += 1;
+                s.shared_bytes += l;
+            }
+        } else if self.seen.insert(oid) {
+            s.unique_pieces += 1;
+            s.unique_bytes += l;
+        }
+    }
+
+    /// Split a single stream of data
+    pub fn stream(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut c = self.kind.chunker(&self.size);
+        let mut buf = vec![0u8;1 << 16];
+        let mut cur = vec![];
+
+        loop {
+            let l = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(l) => l,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.stats.bytes += l as u64;
+
+            let mut data = &buf[..l];
+            while !data.is_empty() {
+                let start = Instant::now();
+                let used = c.push(data);
+                self.stats.split_time += start.elapsed();
+
+                if used == 0 {
+                    cur.extend_from_slice(data);
+                    break;
+                }
+
+                cur.extend_from_slice(&data[..used]);
+                self.piece(&cur);
+                cur.clear();
+                data = &data[used..];
+            }
+        }
+
+        if !cur.is_empty() {
+            self.piece(&cur);
+        }
+
+        Ok(())
+    }
+
+    /// Split all of `input`. Pieces already seen by this `Bench` are counted as duplicates.
+    pub fn add(&mut self, input: &Input) -> io::Result<()> {
+        input.for_each(&mut |r: &mut dyn Read| self.stream(r))
+    }
+
+    /// Return the stats accumulated so far & start over, while still remembering the pieces seen
+    pub fn take_stats(&mut self) -> Stats {
+        self.before.extend(self.seen.drain());
+        ::std::mem::replace(&mut self.stats, Stats::new())
+    }
+}
+
+/// The result of benchmarking one chunker configuration
+pub struct Run {
+    pub kind: ::vblock::ChunkerKind,
+    pub size: ::vblock::PieceSize,
+    pub stats: Stats,
+    /// Stats of a second version of the input, split after the first
+    pub next: Option<Stats>,
+}
+
+impl Run {
+    /// Split `input` and then (if present) `next` with the given configuration
+    pub fn new(kind: ::vblock::ChunkerKind, size: ::vblock::PieceSize, input: &Input, next: Option<&Input>)
+        -> io::Result<Self>
+    {
+        let mut b = Bench::new(kind, size.clone());
+        b.add(input)?;
+        let stats = b.take_stats();
+        let next = match next {
+            Some(n) => {
+                b.add(n)?;
+                Some(b.take_stats())
+            },
+            None => None,
+        };
+
+        Ok(Run {
+            kind: kind,
+            size: size,
+            stats: stats,
+            next: next,
+        })
+    }
+
+    /// Fraction of the second version's distinct pieces (by size) that are also in the first,
+    /// i.e. the size of the intersection of the two versions' sets of pieces
+    pub fn shared(&self) -> Option<f64> {
+        self.next.as_ref().map(|n| {
+            let distinct = n.shared_bytes + n.unique_bytes;
+            if distinct == 0 {
+                0.0
+            } else {
+                n.shared_bytes as f64 / distinct as f64
+            }
+        })
+    }
+
+    /// Number of distinct pieces of the second version that are also in the first, i.e. the
+    /// pieces that survived the changes between versions
+    pub fn reused_pieces(&self) -> Option<u64> {
+        self.next.as_ref().map(|n| n.shared_pieces)
+    }
+}
+
+/// How to display a set of `Run`s
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Format {
+    /// Detailed output, including a histogram, for each run
+    Report,
+    Table,
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(n: &str) -> Option<Self> {
+        match n {
+            "report" => Some(Format::Report),
+            "table" => Some(Format::Table),
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+const COLUMNS: [&'static str;12] = [
+    "chunker", "min", "avg", "max", "bytes", "bytes_per_sec", "pieces", "avg_piece", "stddev_piece",
+    "unique_ratio", "shared_ratio", "reused_pieces",
+];
+
+fn columns(r: &Run) -> Vec<String> {
+    vec![
+        r.kind.name().to_owned(),
+        format!("{}", r.size.min),
+        format!("{}", r.size.avg),
+        format!("{}", r.size.max),
+        format!("{}", r.stats.bytes),
+        format!("{:.0}", r.stats.throughput()),
+        format!("{}", r.stats.pieces),
+        format!("{:.1}", r.stats.avg_piece()),
+        format!("{:.1}", r.stats.stddev_piece()),
+        format!("{:.4}", r.stats.dedup_ratio()),
+        r.shared().map(|v| format!("{:.4}", v)).unwrap_or_default(),
+        r.reused_pieces().map(|v| format!("{}", v)).unwrap_or_default(),
+    ]
+}
+
+/// Print a comparison of `runs` in the given format
+pub fn compare(runs: &[Run], f: Format) {
+    match f {
+        Format::Report => {
+            for r in runs {
+                println!("chunker:         {} (min {}, avg {}, max {})", r.kind, r.size.min, r.size.avg, r.size.max);
+                report(&r.stats);
+                if let (Some(v), Some(p), Some(n)) = (r.shared(), r.reused_pieces(), r.next.as_ref()) {
+                    let distinct = n.shared_pieces + n.unique_pieces;
+                    println!("second version:  {} of {} distinct pieces reused ({:.2}% of pieces, {:.2}% of bytes)",
+                             p, distinct, p as f64 * 100.0 / ::std::cmp::max(distinct, 1) as f64, v * 100.0);
+                }
+                println!();
+            }
+        },
+        Format::Table => {
+            let rows: Vec<Vec<String>> = runs.iter().map(columns).collect();
+            let mut w: Vec<usize> = COLUMNS.iter().map(|c| c.len()).collect();
+            for r in &rows {
+                for (i, c) in r.iter().enumerate() {
+                    w[i] = ::std::cmp::max(w[i], c.len());
+                }
+            }
+            let line = |r: &[String]| {
+                let cells: Vec<String> = r.iter().enumerate().map(|(i, c)| format!("{:>1$}", c, w[i])).collect();
+                println!("{}", cells.join("  "));
+            };
+            let head: Vec<String> = COLUMNS.iter().map(|c| c.to_string()).collect();
+            line(&head);
+            for r in &rows {
+                line(r);
+            }
+        },
+        Format::Csv => {
+            println!("{}", COLUMNS.join(","));
+            for r in runs {
+                println!("{}", columns(r).join(","));
+            }
+        },
+        Format::Json => {
+            println!("[");
+            for (n, r) in runs.iter().enumerate() {
+                let fields: Vec<String> = COLUMNS.iter().zip(columns(r)).map(|(k, v)| {
+                    if *k == "chunker" {
+                        format!("\"{}\": \"{}\"", k, v)
+                    } else if v.is_empty() {
+                        format!("\"{}\": null", k)
+                    } else {
+                        format!("\"{}\": {}", k, v)
+                    }
+                }).collect();
+                println!("  {{{}}}{}", fields.join(", "), if n + 1 == runs.len() { "" } else { "," });
+            }
+            println!("]");
+        },
+    }
+}
+
+fn size_str(b: u64) -> String {
+    const UNITS: [&'static str;5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = b as f64;
+    let mut u = 0;
+    while v >= 1024.0 && u + 1 < UNITS.len() {
+        v /= 1024.0;
+        u += 1;
+    }
+    if u == 0 {
+        format!("{} {}", b, UNITS[0])
+    } else {
+        format!("{:.1} {}", v, UNITS[u])
+    }
+}
+
+/// Print `s` in a human readable form
+pub fn report(s: &Stats) {
+    println!("bytes:           {} ({})", s.bytes, size_str(s.bytes));
+    println!("pieces:          {} ({} unique)", s.pieces, s.unique_pieces);
+    println!("piece size:      min {}, avg {:.0}, max {}, stddev {:.0}",
+             s.min_piece, s.avg_piece(), s.max_piece, s.stddev_piece());
+    println!("unique bytes:    {} ({:.2}% of input)", s.unique_bytes, s.dedup_ratio() * 100.0);
+    println!("split time:      {:.3} s ({}/s)", ::secs(s.split_time), size_str(s.throughput() as u64));
+    println!("piece size histogram:");
+    let first = s.hist.iter().position(|&c| c != 0);
+    let last = s.hist.iter().rposition(|&c| c != 0);
+    if let (Some(first), Some(last)) = (first, last) {
+        let most = *s.hist.iter().max().unwrap();
+        for i in first..(last + 1) {
+            let c = s.hist[i];
+            let bar = (c * 50 + most - 1) / most;
+            println!("  [{:>10}, {:>10}) {:>8} {}",
+                     size_str(1 << i), size_str(1 << (i + 1)), c,
+                     ::std::iter::repeat('#').take(bar as usize).collect::<String>());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bench,Edit,Mutations,Run,Stats};
+
+    /// Stats of pieces of 1, 2, 3, 4, 4 (a duplicate) & 10 bytes
+    fn known() -> Stats {
+        let mut b = Bench::new(::vblock::ChunkerKind::Gear, ::vblock::PieceSize::default());
+        for p in &[&b"a"[..], b"bc", b"def", b"ghij", b"ghij", b"0123456789"] {
+            b.stats.bytes += p.len() as u64;
+            b.piece(p);
+        }
+        b.take_stats()
+    }
+
+    #[test]
+    fn stats() {
+        let s = known();
+        assert_eq!((s.bytes, s.pieces, s.unique_pieces, s.unique_bytes), (24, 6, 5, 20));
+        assert_eq!((s.min_piece, s.max_piece), (1, 10));
+        assert_eq!(s.avg_piece(), 4.0);
+        // sqrt(146 / 6 - 4 * 4)
+        assert!((s.stddev_piece() - 2.886_751).abs() < 1e-6, "{}", s.stddev_piece());
+
+        let empty = Stats::new();
+        assert_eq!((empty.avg_piece(), empty.stddev_piece(), empty.throughput()), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn histogram() {
+        let s = known();
+        // [1, 2): 1, [2, 4): 2 & 3, [4, 8): both 4s, [8, 16): 10
+        assert_eq!(&s.hist[..5], &[1, 2, 2, 1, 0]);
+        assert_eq!(s.hist.iter().sum::<u64>(), s.pieces);
+    }
+
+    /// A run over two versions, each given as its pieces
+    fn two_versions(a: &[&[u8]], b: &[&[u8]]) -> Run {
+        let size = ::vblock::PieceSize::default();
+        let mut bench = Bench::new(::vblock::ChunkerKind::Gear, size.clone());
+        let mut version = |pieces: &[&[u8]]| {
+            for p in pieces {
+                bench.stats.bytes += p.len() as u64;
+                bench.piece(p);
+            }
+            bench.take_stats()
+        };
+        let stats = version(a);
+        let next = version(b);
+        Run { kind: ::vblock::ChunkerKind::Gear, size: size, stats: stats, next: Some(next) }
+    }
+
+    #[test]
+    fn shared() {
+        // "xx" repeats within the second version, but isn't in the first
+        let r = two_versions(&[b"aaaa", b"bb", b"cccc"], &[b"aaaa", b"xx", b"xx", b"xx", b"cccc", b"aaaa"]);
+        let n = r.next.as_ref().unwrap();
+        assert_eq!((n.shared_pieces, n.shared_bytes, n.unique_pieces, n.unique_bytes), (2, 8, 1, 2));
+        assert_eq!(r.shared(), Some(0.8));
+        assert_eq!(r.reused_pieces(), Some(2));
+
+        let r = two_versions(&[b"aaaa"], &[b"bb"]);
+        assert_eq!(r.shared(), Some(0.0));
+        assert_eq!(r.reused_pieces(), Some(0));
+    }
+
+    #[test]
+    fn dedup_ratio() {
+        assert_eq!(known().dedup_ratio(), 20.0 / 24.0);
+        assert_eq!(Stats::new().dedup_ratio(), 1.0);
+    }
+
+    #[test]
+    fn mutations_reproducible() {
+        let data: Vec<u8> = (0..10_000u32).map(|x| x as u8).collect();
+        let m = Mutations { count: 10, edits: vec![Edit::Insert, Edit::Delete, Edit::Flip], seed: 5 };
+        let a = m.apply(&data, 0);
+        assert_eq!(a, m.apply(&data, 0));
+        assert!(a != data);
+        assert!(m.apply(&data, 1) != a);
+
+        let none = Mutations { count: 0, ..m.clone() };
+        assert_eq!(none.apply(&data, 0), data);
+    }
+
+    #[test]
+    fn mutations_flip_keeps_len() {
+        let data = vec![0u8;100];
+        let m = Mutations { count: 3, edits: vec![Edit::Flip], seed: 1 };
+        let a = m.apply(&data, 0);
+        assert_eq!(a.len(), data.len());
+        assert!(a.iter().filter(|b| **b != 0).count() <= 3);
+    }
+}
@@ -0,0 +1,611 @@
+//! Incremental (streaming) blob storage
+//!
+//! `BlobWriter` splits data as it is written, so a blob never needs to be held in memory in its
+//! entirety (except with `BlobLayout::Chunked`, which needs the whole piece list to split it).
+//!
+//! When the store is configured with more than one thread, completed pieces are handed to that
+//! many worker threads, which hash & store them while the writer carries on reading & splitting.
+//! Each piece keeps the place in the index it was split into, so the resulting oids do not depend
+//! on the number of threads or on scheduling.
+//!
+//! `copy_blob` is the reverse: it walks the index of a blob (or the list of a `Kind::Blob`) &
+//! writes the pieces out in order, with worker threads fetching & verifying upcoming pieces so
+//! reads from slow storage overlap. The walk only loads index objects as the pieces below them are
+//! needed, so memory use doesn't grow with the size of the blob.
+//!
+//! The reading threads are scoped (`thread::scope`) & borrow the `Store`, the same way `Object`
+//! does. The writing threads live as long as their `BlobWriter`, which no scope covers, so they
+//! use a handle of their own on the store (`Store::try_clone`).
+//!
+//! Runs of zeros written with `BlobWriter::write_hole` (the holes of sparse files) are recorded in
+//! the index as entries whose oid is all zeros, rather than being stored as pieces.
+use std::collections::{BTreeMap,VecDeque};
+use std::io;
+use std::fs::File;
+use std::io::{Cursor,Read,Seek,SeekFrom,Write};
+use std::mem;
+use std::sync::{Arc,Mutex,mpsc};
+use std::thread;
+
+use chunk::Chunker;
+use config::BlobLayout;
+use {Store,Kind,Oid,Object,IndexEntry,read_u64,read_index_entry,read_piece_entry};
+
+/// The oid recorded in the index for a hole. No object can have it.
+pub(crate) fn hole_oid(store: &Store) -> Oid {
+    Oid::from_bytes(vec![0u8; store.config().hash.oid_len()])
+}
+
+pub(crate) fn is_hole(oid: &Oid) -> bool {
+    oid.as_bytes().iter().all(|&b| b == 0)
+}
+
+fn worker_exited() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "hashing thread panicked")
+}
+
+/// Hashes & stores pieces on worker threads that live as long as the `BlobWriter` feeding them.
+/// Pieces are handed over through a bounded channel, so the writer only waits for the workers when
+/// they fall behind. Each piece is tagged with its position in the index, where its oid goes once
+/// it is stored.
+struct Pipeline {
+    /// pieces (with their kind prefix) for the workers. `None` once they have been told to exit.
+    tx: Option<mpsc::SyncSender<(usize, Vec<u8>)>>,
+    /// oids of the pieces stored (or why they couldn't be), from the workers
+    rx: mpsc::Receiver<(usize, io::Result<Oid>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    /// pieces submitted whose oid has not come back yet
+    outstanding: usize,
+}
+
+impl Pipeline {
+    fn new(store: &Store, threads: usize) -> io::Result<Self> {
+        // the workers outlive any borrow of `store`, so they share a handle of their own
+        let store = Arc::new(store.try_clone()?);
+        let (tx, work) = mpsc::sync_channel::<(usize, Vec<u8>)>(threads * 4);
+        let work = Arc::new(Mutex::new(work));
+        let (done, rx) = mpsc::channel();
+
+        let workers = (0..threads).map(|_| {
+            let store = store.clone();
+            let work = work.clone();
+            let done = done.clone();
+            thread::spawn(move || loop {
+                // the lock is only held while waiting for a piece
+                let next = match work.lock() {
+                    Ok(w) => w.recv(),
+                    Err(_) => return,
+                };
+                let (i, d) = match next {
+                    Ok(v) => v,
+                    // the writer is done (or gone)
+                    Err(_) => return,
+                };
+                let oid = store.config().hash.oid(&d);
+                let r = store.write_object(&oid, &d).map(|_| oid);
+                if done.send((i, r)).is_err() {
+                    return;
+                }
+            })
+        }).collect();
+
+        Ok(Pipeline {
+            tx: Some(tx),
+            rx: rx,
+            workers: workers,
+            outstanding: 0,
+        })
+    }
+
+    fn record(&mut self, done: (usize, io::Result<Oid>), entries: &mut [(u64, Option<Oid>)]) -> io::Result<()> {
+        self.outstanding -= 1;
+        entries[done.0].1 = Some(done.1?);
+        Ok(())
+    }
+
+    /// Queue the piece `d` for `entries[i]`, waiting only if the queue is full
+    fn submit(&mut self, i: usize, d: Vec<u8>, entries: &mut [(u64, Option<Oid>)]) -> io::Result<()> {
+        // pick up what has been stored meanwhile, so errors show up early
+        while let Ok(done) = self.rx.try_recv() {
+            self.record(done, entries)?;
+        }
+        self.tx.as_ref().unwrap().send((i, d)).map_err(|_| worker_exited())?;
+        self.outstanding += 1;
+        Ok(())
+    }
+
+    /// Wait for every piece submitted to be stored & the workers to exit
+    fn finish(mut self, entries: &mut [(u64, Option<Oid>)]) -> io::Result<()> {
+        self.tx = None;
+        while self.outstanding > 0 {
+            let done = self.rx.recv().map_err(|_| worker_exited())?;
+            self.record(done, entries)?;
+        }
+        for w in self.workers.drain(..) {
+            w.join().map_err(|_| worker_exited())?;
+        }
+        Ok(())
+    }
+}
+
+/// Stores the data written to it as a blob. Obtained from `Store::blob_writer`.
+///
+/// Call `commit` to finish the blob and obtain its oid.
+pub struct BlobWriter<'a> {
+    store: &'a Store,
+    chunker: Chunker,
+
+    /// the piece currently being accumulated, prefixed by its kind
+    cur: Vec<u8>,
+    /// lengths & oids of the pieces & holes written so far. A piece's oid is `None` while the
+    /// pipeline is storing it.
+    entries: Vec<(u64, Option<Oid>)>,
+    /// started with the first piece if the store uses more than one thread
+    pipeline: Option<Pipeline>,
+
+    /// all data written, for `BlobLayout::Chunked`
+    chunked: Option<Vec<u8>>,
+}
+
+impl<'a> BlobWriter<'a> {
+    pub(crate) fn new(store: &'a Store) -> Self {
+        BlobWriter {
+            store: store,
+            chunker: store.config().new_chunker(),
+            cur: Kind::Piece.as_bytes().to_vec(),
+            entries: vec![],
+            pipeline: None,
+            chunked: match store.config().blob_layout {
+                BlobLayout::Chunked => Some(vec![]),
+                _ => None,
+            },
+        }
+    }
+
+    fn finish_piece(&mut self) -> io::Result<()> {
+        let d = mem::replace(&mut self.cur, Kind::Piece.as_bytes().to_vec());
+        let len = (d.len() - Kind::len()) as u64;
+        if self.pipeline.is_none() && self.store.threads() > 1 {
+            self.pipeline = Some(Pipeline::new(self.store, self.store.threads())?);
+        }
+        match self.pipeline {
+            Some(ref mut p) => {
+                self.entries.push((len, None));
+                let i = self.entries.len() - 1;
+                p.submit(i, d, &mut self.entries)
+            },
+            None => {
+                let oid = self.store.config().hash.oid(&d);
+                self.store.write_object(&oid, &d)?;
+                self.entries.push((len, Some(oid)));
+                Ok(())
+            },
+        }
+    }
+
+    /// Append `len` zero bytes, recorded as a hole rather than stored.
+    ///
+    /// The current piece is ended first, so pieces never span a hole.
+    pub fn write_hole(&mut self, len: u64) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        if self.chunked.is_some() {
+            // this layout has no holes
+            let z = [0u8;4096];
+            let mut rem = len;
+            while rem > 0 {
+                let n = ::std::cmp::min(rem, z.len() as u64) as usize;
+                self.write_all(&z[..n])?;
+                rem -= n as u64;
+            }
+            return Ok(());
+        }
+
+        if self.cur.len() > Kind::len() {
+            self.finish_piece()?;
+        }
+        self.chunker.reset();
+
+        if let Some(&mut (ref mut l, Some(ref o))) = self.entries.last_mut() {
+            if is_hole(o) {
+                *l += len;
+                return Ok(());
+            }
+        }
+        self.entries.push((len, Some(hole_oid(self.store))));
+        Ok(())
+    }
+
+    /// Store any remaining data & the index over all of the pieces, returning the oid of the blob
+    pub fn commit(mut self) -> io::Result<Oid> {
+        if let Some(d) = self.chunked.take() {
+            return self.store.put_blob_inner(Kind::Piece, d);
+        }
+
+        if self.cur.len() > Kind::len() {
+            self.finish_piece()?;
+        }
+
+        if let Some(p) = self.pipeline.take() {
+            p.finish(&mut self.entries)?;
+        }
+
+        let entries = mem::replace(&mut self.entries, vec![]).into_iter()
+            .map(|(len, oid)| IndexEntry { len: len, oid: oid.unwrap() })
+            .collect();
+        self.store.put_index_tree(entries)
+    }
+}
+
+impl<'a> Write for BlobWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut d) = self.chunked {
+            d.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        let mut data = buf;
+        while !data.is_empty() {
+            let used = self.chunker.push(data);
+            if used == 0 {
+                self.cur.extend_from_slice(data);
+                break;
+            }
+
+            self.cur.extend_from_slice(&data[..used]);
+            self.finish_piece()?;
+            data = &data[used..];
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A part of a blob's content, as found by `Parts`
+enum Part {
+    /// A piece, with the length its index gives (legacy blob lists don't record one)
+    Data(Oid, Option<u64>),
+    Hole(u64),
+}
+
+/// An index object being read by `Parts`
+struct Level<'a> {
+    depth: u64,
+    /// positioned at the next entry
+    o: Object<'a>,
+    /// the oid of this index & the length its parent gives it, `None` for the top
+    expect: Option<(Oid, u64)>,
+    /// sum of the lengths of the entries read so far
+    seen: u64,
+}
+
+/// The concatenated content of the pieces listed in `list`: one level of the recursive list of a
+/// `Kind::Blob`
+struct LegacyLevel<'a> {
+    store: &'a Store,
+    list: Box<dyn Read + 'a>,
+    cur: Cursor<Vec<u8>>,
+}
+
+impl<'a> Read for LegacyLevel<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.cur.read(buf)?;
+            if n != 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let e = match read_piece_entry(&mut self.list, self.store.config().hash.oid_len())? {
+                Some(v) => v,
+                None => return Ok(0),
+            };
+            self.cur = Cursor::new(fetch_piece(self.store, &e.oid, None)?);
+        }
+    }
+}
+
+/// Walks the parts of a blob in order. Index objects (and the lists of `Kind::Blob`s) are loaded
+/// & checked as the walk reaches them, so only one object per level is held in memory. Pieces
+/// are left to `fetch_piece`.
+enum Parts<'a> {
+    Index(&'a Store, Vec<Level<'a>>),
+    /// the list of piece oids at the bottom of a `Kind::Blob`
+    Legacy(&'a Store, Box<dyn Read + 'a>),
+}
+
+impl<'a> Parts<'a> {
+    /// Start walking `o`, which must be an `Index` or a `Blob`
+    fn new(store: &'a Store, mut o: Object<'a>) -> io::Result<Self> {
+        match o.kind() {
+            Kind::Index => {
+                let depth = read_u64(&mut o)?;
+                if depth == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "index has depth 0"));
+                }
+                Ok(Parts::Index(store, vec![Level { depth: depth, o: o, expect: None, seen: 0 }]))
+            },
+            Kind::Blob => {
+                // each level is led by the kind of the level below
+                let mut list: Box<dyn Read + 'a> = Box::new(o);
+                loop {
+                    match Kind::read_from(&mut list)? {
+                        Kind::Piece => return Ok(Parts::Legacy(store, list)),
+                        Kind::Blob => {
+                            list = Box::new(LegacyLevel { store: store, list: list, cur: Cursor::new(vec![]) });
+                        },
+                        k => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                       format!("Sub-kind Kind::{:?} not allowed", k))),
+                    }
+                }
+            },
+            k => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("object {} is a {:?}, not a blob", o.oid(), k))),
+        }
+    }
+
+    fn next(&mut self) -> io::Result<Option<Part>> {
+        let (store, stack) = match *self {
+            Parts::Index(store, ref mut stack) => (store, stack),
+            Parts::Legacy(store, ref mut list) => {
+                return Ok(read_piece_entry(list, store.config().hash.oid_len())?
+                          .map(|e| Part::Data(e.oid, None)));
+            },
+        };
+
+        loop {
+            let e = match stack.last_mut() {
+                None => return Ok(None),
+                Some(l) => read_index_entry(&mut l.o, store.config().hash.oid_len())?,
+            };
+            let l = stack.last_mut().unwrap();
+            let e = match e {
+                Some(v) => v,
+                None => {
+                    if let Some((ref oid, len)) = l.expect {
+                        if l.seen != len {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("object {} has length {}, index says {}",
+                                                              oid, l.seen, len)));
+                        }
+                    }
+                    stack.pop();
+                    continue;
+                },
+            };
+            l.seen += e.len;
+
+            let depth = l.depth;
+            if is_hole(&e.oid) {
+                if depth != 1 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("hole in index of depth {}", depth)));
+                }
+                return Ok(Some(Part::Hole(e.len)));
+            }
+            if depth == 1 {
+                return Ok(Some(Part::Data(e.oid, Some(e.len))));
+            }
+
+            let mut p = match store.get(&e.oid)? {
+                Some(v) => v,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("missing object {}", e.oid))),
+            };
+            if p.kind() != Kind::Index {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("object {} is a {:?}, not allowed in index of depth {}",
+                                                  e.oid, p.kind(), depth)));
+            }
+
+            let sub_depth = read_u64(&mut p)?;
+            if sub_depth != depth - 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("index {} has depth {}, expected {}", e.oid, sub_depth, depth - 1)));
+            }
+            stack.push(Level { depth: sub_depth, o: p, expect: Some((e.oid, e.len)), seen: 0 });
+        }
+    }
+}
+
+/// Load & check a single piece, with the length its index gives if any
+fn fetch_piece(store: &Store, oid: &Oid, len: Option<u64>) -> io::Result<Vec<u8>>
+{
+    let p = match store.get(oid)? {
+        Some(v) => v,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("missing object {}", oid))),
+    };
+    if p.kind() != Kind::Piece {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("object {} is a {:?}, expected a piece", oid, p.kind())));
+    }
+
+    let d = p.as_ref();
+    if let Some(len) = len {
+        if d.len() as u64 != len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("object {} has length {}, index says {}", oid, d.len(), len)));
+        }
+    }
+    Ok(d.to_vec())
+}
+
+/// Where `copy_blob_to` sends the content of a blob
+pub(crate) trait Output {
+    fn data(&mut self, d: &[u8]) -> io::Result<()>;
+    /// `len` zero bytes
+    fn hole(&mut self, len: u64) -> io::Result<()>;
+    /// Called after the last data or hole
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes holes out as zeros
+pub(crate) struct Zeros<W: Write>(pub W);
+
+impl<W: Write> Output for Zeros<W> {
+    fn data(&mut self, d: &[u8]) -> io::Result<()> {
+        self.0.write_all(d)
+    }
+
+    fn hole(&mut self, len: u64) -> io::Result<()> {
+        io::copy(&mut io::repeat(0).take(len), &mut self.0).map(|_| ())
+    }
+}
+
+/// Recreates holes by seeking over them
+pub(crate) struct Sparse<'a>(pub &'a mut File);
+
+impl<'a> Output for Sparse<'a> {
+    fn data(&mut self, d: &[u8]) -> io::Result<()> {
+        self.0.write_all(d)
+    }
+
+    fn hole(&mut self, len: u64) -> io::Result<()> {
+        self.0.seek(SeekFrom::Current(len as i64)).map(|_| ())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // a trailing hole has only moved the offset, extend the file to it
+        let end = self.0.seek(SeekFrom::Current(0))?;
+        self.0.set_len(end)
+    }
+}
+
+/// Only checks that the content can be read
+pub(crate) struct Discard;
+
+impl Output for Discard {
+    fn data(&mut self, _d: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn hole(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write the parts in order, fetching up to `window` pieces ahead on `threads` threads. Returns
+/// the length of the content.
+fn copy_parts(store: &Store, parts: &mut Parts, threads: usize, window: usize, out: &mut dyn Output)
+    -> io::Result<u64>
+{
+    let (jobs_tx, jobs_rx) = mpsc::channel::<(usize, Oid, Option<u64>)>();
+    let (done_tx, done_rx) = mpsc::channel::<(usize, io::Result<Vec<u8>>)>();
+    let jobs_rx = Mutex::new(jobs_rx);
+
+    thread::scope(|s| {
+        for _ in 0..threads {
+            let done_tx = done_tx.clone();
+            let jobs_rx = &jobs_rx;
+            s.spawn(move || {
+                loop {
+                    let (i, oid, len) = match jobs_rx.lock().unwrap().recv() {
+                        Ok(v) => v,
+                        Err(_) => return,
+                    };
+                    if done_tx.send((i, fetch_piece(store, &oid, len))).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(done_tx);
+
+        let r = (|| -> io::Result<u64> {
+            // parts taken from the walk but not yet written, with their position
+            let mut ahead = VecDeque::new();
+            let mut pending = BTreeMap::new();
+            let mut taken = 0;
+            let mut walked = false;
+            let mut total = 0;
+            loop {
+                while !walked && ahead.len() < window {
+                    match parts.next()? {
+                        Some(p) => {
+                            if let Part::Data(ref oid, len) = p {
+                                // the receiver outlives this closure, so this can't fail
+                                jobs_tx.send((taken, oid.clone(), len)).unwrap();
+                            }
+                            ahead.push_back((taken, p));
+                            taken += 1;
+                        },
+                        None => walked = true,
+                    }
+                }
+
+                match ahead.pop_front() {
+                    None => return Ok(total),
+                    Some((_, Part::Hole(len))) => {
+                        out.hole(len)?;
+                        total += len;
+                    },
+                    Some((next, Part::Data(..))) => {
+                        let d = loop {
+                            if let Some(d) = pending.remove(&next) {
+                                break d;
+                            }
+                            let (i, d) = done_rx.recv().map_err(|_|
+                                io::Error::new(io::ErrorKind::Other, "read-ahead thread exited unexpectedly"))?;
+                            pending.insert(i, d);
+                        };
+                        let d = d?;
+                        out.data(&d)?;
+                        total += d.len() as u64;
+                    },
+                }
+            }
+        })();
+
+        // closing the job queue makes the workers exit
+        drop(jobs_tx);
+        r
+    })
+}
+
+/// Send the content of the blob `oid` to `out`. See `Store::copy_blob`.
+pub(crate) fn copy_blob_to(store: &Store, oid: &Oid, out: &mut dyn Output) -> io::Result<Option<u64>>
+{
+    let o = match store.get(oid)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    if o.kind() == Kind::Piece {
+        out.data(o.as_ref())?;
+        out.finish()?;
+        return Ok(Some(o.as_ref().len() as u64));
+    }
+
+    let mut parts = Parts::new(store, o)?;
+    let threads = store.threads();
+    let total = if threads > 1 {
+        let window = ::std::cmp::max(store.read_ahead(), threads);
+        copy_parts(store, &mut parts, threads, window, out)?
+    } else {
+        let mut total = 0;
+        while let Some(p) = parts.next()? {
+            match p {
+                Part::Hole(len) => {
+                    out.hole(len)?;
+                    total += len;
+                },
+                Part::Data(oid, len) => {
+                    let d = fetch_piece(store, &oid, len)?;
+                    out.data(&d)?;
+                    total += d.len() as u64;
+                },
+            }
+        }
+        total
+    };
+    out.finish()?;
+
+    Ok(Some(total))
+}
@@ -0,0 +1,172 @@
+//! The file metadata cache, used by backup to skip reading files that have not changed
+//!
+//! Kept in the `cache/` area of a store, one file per backup source, named by the hash of the
+//! source path. It maps the path of each file (relative to the source) to the device, inode,
+//! size, modification & change times the file had when it was read, and the oid of its content.
+//!
+//! The file is a sequence of records: the length of the path (`u64`), the path, then device,
+//! inode & size (`u64`), the two times as seconds (`i64`) & nanoseconds (`u64`), and the oid.
+//! Integers are little endian.
+//!
+//! It is only a cache: one that can't be read is treated as empty.
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use byteorder::{ByteOrder,LittleEndian};
+use openat::Dir;
+
+use fs;
+use fs::DirVblockExt;
+use tree::Time;
+use {Store,Oid};
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub(crate) struct CacheEntry {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime: Time,
+    pub ctime: Time,
+    pub oid: Oid,
+}
+
+impl CacheEntry {
+    pub fn new(m: &::std::fs::Metadata, oid: Oid) -> Self {
+        CacheEntry {
+            dev: m.dev(),
+            ino: m.ino(),
+            size: m.len(),
+            mtime: Time { secs: m.mtime(), nsecs: m.mtime_nsec() as u32 },
+            ctime: Time { secs: m.ctime(), nsecs: m.ctime_nsec() as u32 },
+            oid: oid,
+        }
+    }
+
+    /// Whether `m` describes the same, unchanged, file
+    pub fn matches(&self, m: &::std::fs::Metadata) -> bool {
+        let e = CacheEntry::new(m, self.oid.clone());
+        *self == e
+    }
+}
+
+#[derive(Debug,Default)]
+pub(crate) struct FileCache {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+}
+
+fn cache_dir(store: &Store) -> io::Result<Dir> {
+    store.dir().create_dir_open("cache")
+}
+
+fn cache_name(store: &Store, source: &Path) -> String {
+    format!("{}", store.config().hash.oid(source.as_os_str().as_bytes()))
+}
+
+fn put_u64(out: &mut Vec<u8>, v: u64) {
+    let mut b = [0u8;8];
+    LittleEndian::write_u64(&mut b, v);
+    out.extend(&b[..]);
+}
+
+fn put_time(out: &mut Vec<u8>, t: Time) {
+    put_u64(out, t.secs as u64);
+    put_u64(out, t.nsecs as u64);
+}
+
+fn take<'a>(d: &mut &'a [u8], len: u64) -> Option<&'a [u8]> {
+    if (d.len() as u64) < len {
+        return None;
+    }
+    let (a, b) = d.split_at(len as usize);
+    *d = b;
+    Some(a)
+}
+
+fn take_u64(d: &mut &[u8]) -> Option<u64> {
+    take(d, 8).map(LittleEndian::read_u64)
+}
+
+fn take_time(d: &mut &[u8]) -> Option<Time> {
+    let secs = take_u64(d)? as i64;
+    let nsecs = take_u64(d)?;
+    Some(Time { secs: secs, nsecs: nsecs as u32 })
+}
+
+impl FileCache {
+    /// The cache for backups of `source`, empty if there is none
+    pub fn load(store: &Store, source: &Path) -> io::Result<Self> {
+        let d = fs::read_file(&cache_dir(store)?, &cache_name(store, source))?;
+        Ok(d.and_then(|d| Self::from_bytes(&d, store.config().hash.oid_len())).unwrap_or_default())
+    }
+
+    pub fn save(&self, store: &Store, source: &Path) -> io::Result<()> {
+        fs::replace_file(&cache_dir(store)?, &cache_name(store, source), &self.to_bytes(), 0o600)
+    }
+
+    pub fn get(&self, path: &[u8]) -> Option<&CacheEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn insert(&mut self, path: Vec<u8>, e: CacheEntry) {
+        self.entries.insert(path, e);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for (p, e) in &self.entries {
+            put_u64(&mut out, p.len() as u64);
+            out.extend(p);
+            put_u64(&mut out, e.dev);
+            put_u64(&mut out, e.ino);
+            put_u64(&mut out, e.size);
+            put_time(&mut out, e.mtime);
+            put_time(&mut out, e.ctime);
+            out.extend(e.oid.as_bytes());
+        }
+        out
+    }
+
+    fn from_bytes(mut d: &[u8], oid_len: usize) -> Option<Self> {
+        let mut c = FileCache::default();
+        while !d.is_empty() {
+            let l = take_u64(&mut d)?;
+            let p = take(&mut d, l)?.to_owned();
+            let e = CacheEntry {
+                dev: take_u64(&mut d)?,
+                ino: take_u64(&mut d)?,
+                size: take_u64(&mut d)?,
+                mtime: take_time(&mut d)?,
+                ctime: take_time(&mut d)?,
+                oid: Oid::from_bytes(take(&mut d, oid_len as u64)?),
+            };
+            c.entries.insert(p, e);
+        }
+        Some(c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileCache,CacheEntry};
+    use tree::Time;
+    use Oid;
+
+    #[test]
+    fn cache_round_trip() {
+        let mut c = FileCache::default();
+        c.insert(b"a/b".to_vec(), CacheEntry {
+            dev: 1,
+            ino: 2,
+            size: 3,
+            mtime: Time { secs: -4, nsecs: 5 },
+            ctime: Time { secs: 6, nsecs: 999_999_999 },
+            oid: Oid::from_bytes(vec![7u8;32]),
+        });
+        let d = c.to_bytes();
+        let c2 = FileCache::from_bytes(&d, 32).unwrap();
+        assert_eq!(c.get(b"a/b"), c2.get(b"a/b"));
+        assert!(FileCache::from_bytes(&d[..d.len() - 1], 32).is_none());
+    }
+}
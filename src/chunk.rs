@@ -0,0 +1,347 @@
+//! Content defined chunking
+//!
+//! `Chunker` wraps the splitters of `hash_roll` behind one incremental interface: data is `push`ed
+//! in, and the return value indicates where (if anywhere) in that data the current piece ends.
+//!
+//! `PieceSize` bounds the pieces produced. FastCDC's normalized chunking is built around the bounds,
+//! so `hash_roll` applies them. For the other splitters bytes before the minimum size are not
+//! hashed (except by bup, whose rolling state carries on across pieces), and a piece that reaches
+//! the maximum size is cut there even if no boundary was found.
+//!
+//! All splitters other than bup start afresh at each piece boundary, so the boundaries found only
+//! depend on the data since the previous boundary, not on how it was divided between calls to
+//! `push`.
+use std::fmt;
+use std::io;
+use std::cmp;
+use hash_roll::{ChunkIncr,ToChunkIncr};
+
+/// Splitting algorithm, recorded in a store's `Config`
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum ChunkerKind {
+    /// bup's rollsum (`hash_roll::bup`). The original & default algorithm.
+    Bup,
+    /// `gzip --rsyncable` (`hash_roll::gzip`), with a window of the average piece size
+    Rsyncable,
+    /// Gear hash (`hash_roll::gear`)
+    Gear,
+    /// FastCDC's normalized chunking of a gear hash (`hash_roll::fastcdc`)
+    FastCdc,
+    /// Cyclic polynomial hash (`hash_roll::buzhash`)
+    Buzhash,
+    /// zpaq's fragmentation (`hash_roll::zpaq`)
+    Zpaq,
+}
+
+impl ChunkerKind {
+    pub fn all() -> &'static [ChunkerKind] {
+        static ALL: [ChunkerKind;6] = [
+            ChunkerKind::Bup,
+            ChunkerKind::Rsyncable,
+            ChunkerKind::Gear,
+            ChunkerKind::FastCdc,
+            ChunkerKind::Buzhash,
+            ChunkerKind::Zpaq,
+        ];
+        &ALL[..]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChunkerKind::Bup => "bup",
+            ChunkerKind::Rsyncable => "rsyncable",
+            ChunkerKind::Gear => "gear",
+            ChunkerKind::FastCdc => "fastcdc",
+            ChunkerKind::Buzhash => "buzhash",
+            ChunkerKind::Zpaq => "zpaq",
+        }
+    }
+
+    pub fn from_name(n: &str) -> Option<Self> {
+        ChunkerKind::all().iter().cloned().find(|k| k.name() == n)
+    }
+
+    /// Construct a new splitter of this kind producing pieces bounded by `size`.
+    ///
+    /// `size` must have been accepted by `PieceSize::check` for this kind.
+    pub fn chunker(&self, size: &PieceSize) -> Chunker {
+        // FastCDC enforces the bounds itself
+        let (min, max) = match *self {
+            ChunkerKind::FastCdc => (0, 0),
+            _ => (size.min, size.max),
+        };
+
+        Chunker {
+            kind: *self,
+            size: size.clone(),
+            inner: incr(*self, size),
+            min: min,
+            max: max,
+            len: 0,
+        }
+    }
+}
+
+/// A splitter of `kind` that has seen no data
+fn incr(kind: ChunkerKind, size: &PieceSize) -> Box<dyn ChunkIncr + Send> {
+    let bits = size.avg.trailing_zeros();
+    match kind {
+        ChunkerKind::Bup => Box::new(::hash_roll::bup::RollSum::default().to_chunk_incr()),
+        ChunkerKind::Rsyncable => Box::new(::hash_roll::gzip::GzipRsyncable::with_window(size.avg).to_chunk_incr()),
+        ChunkerKind::Gear => Box::new(::hash_roll::gear::Gear32::with_chunk_bits(bits).to_chunk_incr()),
+        ChunkerKind::FastCdc => {
+            let max = if size.max == 0 { u64::max_value() } else { size.max as u64 };
+            Box::new(::hash_roll::fastcdc::FastCdc::new(&::hash_roll::gear_table::GEAR_64,
+                                                         size.min as u64, size.avg as u64, max)
+                     .to_chunk_incr())
+        },
+        ChunkerKind::Buzhash => Box::new(::hash_roll::buzhash::BuzHash::new_nom(bits as u8).to_chunk_incr()),
+        ChunkerKind::Zpaq => Box::new(::hash_roll::zpaq::Zpaq::with_average_size_pow_2(bits as u8).to_chunk_incr()),
+    }
+}
+
+impl Default for ChunkerKind {
+    fn default() -> Self {
+        ChunkerKind::Bup
+    }
+}
+
+impl fmt::Display for ChunkerKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.name())
+    }
+}
+
+/// Bounds on the size of pieces produced by a `Chunker`
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub struct PieceSize {
+    /// Pieces are never smaller than this, except at the end of the data. `0` for no minimum.
+    pub min: usize,
+    /// Average size the rolling hash targets. Must be a power of 2.
+    pub avg: usize,
+    /// Pieces are cut at this size. `0` for no maximum.
+    pub max: usize,
+}
+
+/// bup's average piece size, which `hash_roll::bup` does not allow changing
+const BUP_AVG: usize = 1 << 13;
+
+impl PieceSize {
+    /// No minimum or maximum. The behaviour of stores created before piece sizes were
+    /// configurable.
+    pub fn unbounded() -> Self {
+        PieceSize {
+            min: 0,
+            avg: BUP_AVG,
+            max: 0,
+        }
+    }
+
+    pub fn check(&self, kind: ChunkerKind) -> io::Result<()> {
+        let e = |m: String| -> io::Result<()> { Err(io::Error::new(io::ErrorKind::InvalidInput, m)) };
+        if !self.avg.is_power_of_two() || self.avg < 1 << 8 || self.avg > 1 << 28 {
+            return e(format!("average piece size {} must be a power of 2 between 256 and 2^28", self.avg));
+        }
+        if kind == ChunkerKind::Bup && self.avg != BUP_AVG {
+            return e(format!("chunker bup only supports an average piece size of {}", BUP_AVG));
+        }
+        if self.min > self.avg {
+            return e(format!("minimum piece size {} is larger than the average {}", self.min, self.avg));
+        }
+        if self.max != 0 && self.max < self.avg {
+            return e(format!("maximum piece size {} is smaller than the average {}", self.max, self.avg));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PieceSize {
+    fn default() -> Self {
+        PieceSize {
+            min: BUP_AVG / 4,
+            avg: BUP_AVG,
+            max: BUP_AVG * 8,
+        }
+    }
+}
+
+/// An incremental content defined splitter
+pub struct Chunker {
+    kind: ChunkerKind,
+    size: PieceSize,
+    inner: Box<dyn ChunkIncr + Send>,
+    /// bounds applied here rather than by `inner`, `0` for none
+    min: usize,
+    max: usize,
+
+    /// bytes in the current piece
+    len: usize,
+}
+
+impl Chunker {
+    /// Feed `data` to the splitter.
+    ///
+    /// Returns the number of bytes of `data` that complete the current piece, or `0` if `data`
+    /// does not contain the end of the current piece (in which case all of `data` has been
+    /// consumed).
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let mut off = 0;
+        while off < data.len() {
+            let rem = &data[off..];
+            if self.len < self.min {
+                // too early for a boundary
+                let skip = cmp::min(self.min - self.len, rem.len());
+                self.skip(&rem[..skip]);
+                self.len += skip;
+                off += skip;
+                continue;
+            }
+
+            let take = if self.max != 0 {
+                cmp::min(self.max - self.len, rem.len())
+            } else {
+                rem.len()
+            };
+
+            if let Some(used) = self.inner.push(&rem[..take]) {
+                // bup's window carries on into the next piece
+                if self.kind != ChunkerKind::Bup {
+                    self.inner = incr(self.kind, &self.size);
+                }
+                self.len = 0;
+                return off + used;
+            }
+
+            self.len += take;
+            off += take;
+            if self.max != 0 && self.len >= self.max {
+                self.reset();
+                return off;
+            }
+        }
+
+        0
+    }
+
+    /// Account for `data`, which is before the minimum piece size & so can't contain a boundary
+    fn skip(&mut self, mut data: &[u8]) {
+        // bup's window carries on across pieces, so it must see every byte. Boundaries it finds
+        // here are too early & are ignored.
+        if self.kind == ChunkerKind::Bup {
+            while let Some(used) = self.inner.push(data) {
+                data = &data[used..];
+            }
+        }
+    }
+
+    /// Forget any partially seen piece, so the next data pushed starts a new piece.
+    pub fn reset(&mut self) {
+        self.inner = incr(self.kind, &self.size);
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+    use super::{ChunkerKind,PieceSize};
+
+    /// Pseudo-random test data (xorshift64)
+    fn random(len: usize) -> Vec<u8> {
+        let mut x: u64 = 1;
+        (0..len).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        }).collect()
+    }
+
+    fn pieces_sized(k: ChunkerKind, size: &PieceSize, data: &[u8], step: usize) -> Vec<usize> {
+        let mut c = k.chunker(size);
+        let mut r = vec![];
+        let mut start = 0;
+        let mut piece_start = 0;
+        while start < data.len() {
+            let end = ::std::cmp::min(start + step, data.len());
+            let used = c.push(&data[start..end]);
+            if used == 0 {
+                start = end;
+            } else {
+                start += used;
+                r.push(start - piece_start);
+                piece_start = start;
+            }
+        }
+        r
+    }
+
+    fn pieces(k: ChunkerKind, data: &[u8], step: usize) -> Vec<usize> {
+        pieces_sized(k, &PieceSize::unbounded(), data, step)
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for k in ChunkerKind::all() {
+            assert_eq!(ChunkerKind::from_name(k.name()), Some(*k));
+        }
+    }
+
+    #[test]
+    fn boundaries_independent_of_push_size() {
+        fn prop(data: Vec<u8>, step: usize) -> bool {
+            let step = step % 1000 + 1;
+            // bup's state is outside our control, skip it.
+            let size = PieceSize { min: 256, avg: 512, max: 1024 };
+            ChunkerKind::all().iter().filter(|k| **k != ChunkerKind::Bup).all(|k| {
+                pieces(*k, &data, data.len() + 1) == pieces(*k, &data, step) &&
+                    pieces_sized(*k, &size, &data, data.len() + 1) == pieces_sized(*k, &size, &data, step)
+            })
+        }
+        quickcheck::quickcheck(prop as fn(Vec<u8>, usize) -> bool)
+    }
+
+    #[test]
+    fn pieces_found() {
+        let data = random(1_000_000);
+        for k in ChunkerKind::all() {
+            let p = pieces(*k, &data, 4096);
+            assert!(p.len() > 1, "{} found no boundaries", k);
+        }
+    }
+
+    #[test]
+    fn sizes_bounded() {
+        fn prop(data: Vec<u8>, zeros: u16) -> bool {
+            let mut data = data;
+            data.extend(::std::iter::repeat(0u8).take(zeros as usize * 4));
+            let size = PieceSize { min: 256, avg: 512, max: 1024 };
+            ChunkerKind::all().iter().filter(|k| **k != ChunkerKind::Bup).all(|k| {
+                let mut c = k.chunker(&size);
+                let mut rem = &data[..];
+                loop {
+                    let used = c.push(rem);
+                    if used == 0 {
+                        return rem.len() <= size.max;
+                    }
+                    if used < size.min || used > size.max {
+                        return false;
+                    }
+                    rem = &rem[used..];
+                }
+            })
+        }
+        quickcheck::quickcheck(prop as fn(Vec<u8>, u16) -> bool)
+    }
+
+    #[test]
+    fn check() {
+        assert!(PieceSize::default().check(ChunkerKind::Bup).is_ok());
+        assert!(PieceSize::unbounded().check(ChunkerKind::Bup).is_ok());
+        assert!(PieceSize { min: 0, avg: 4096, max: 0 }.check(ChunkerKind::Bup).is_err());
+        assert!(PieceSize { min: 0, avg: 4096, max: 0 }.check(ChunkerKind::Gear).is_ok());
+        assert!(PieceSize { min: 0, avg: 4000, max: 0 }.check(ChunkerKind::Gear).is_err());
+        assert!(PieceSize { min: 8192, avg: 4096, max: 0 }.check(ChunkerKind::Gear).is_err());
+        assert!(PieceSize { min: 0, avg: 4096, max: 2048 }.check(ChunkerKind::Gear).is_err());
+    }
+}
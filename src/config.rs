@@ -0,0 +1,310 @@
+//! Store configuration
+//!
+//! Parameters which affect the oids that a store generates (and so the ability to dedup against
+//! data already in the store) are fixed when the store is created and recorded in its `config`
+//! file, which is made up of `name value` lines:
+//!
+//! ```text
+//! hash sha2-512
+//! chunker bup
+//! piece-min 2048
+//! piece-avg 8192
+//! piece-max 65536
+//! blob-layout cdc-tree
+//! fanout 128
+//! ```
+//!
+//! Fields missing from an existing `config` take the value that matches the behaviour of vblock
+//! before the field was introduced, so that stores keep generating the same oids.
+use std::io;
+use std::fmt;
+use openat::Dir;
+
+use fs;
+use fs::invalid_data;
+use Oid;
+use chunk::{ChunkerKind,PieceSize};
+
+/// Hash algorithm used to generate `Oid`s
+///
+/// Names follow the [multihash](https://github.com/multiformats/multihash) table, but unlike a
+/// multihash an `Oid` is the bare digest: neither its bytes nor its hex form carry the algorithm.
+/// Tagging them would change the oid, object path & ref of everything already stored. The
+/// algorithm that made an oid is only known from the `config` of the store holding it, so an oid
+/// printed by `put`, kept in a ref or covered by a signature means nothing without its store.
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum HashAlg {
+    /// SHA-512 (via `sodalite`), 64 byte oids. The original & default algorithm.
+    Sha512,
+    /// SHA-256, 32 byte oids
+    Sha256,
+    /// BLAKE3, 32 byte oids
+    Blake3,
+}
+
+impl HashAlg {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlg::Sha512 => "sha2-512",
+            HashAlg::Sha256 => "sha2-256",
+            HashAlg::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(n: &str) -> Option<Self> {
+        match n {
+            "sha2-512" => Some(HashAlg::Sha512),
+            "sha2-256" => Some(HashAlg::Sha256),
+            "blake3" => Some(HashAlg::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Length in bytes of the `Oid`s generated by this algorithm
+    pub fn oid_len(&self) -> usize {
+        match *self {
+            HashAlg::Sha512 => ::sodalite::HASH_LEN,
+            HashAlg::Sha256 => 32,
+            HashAlg::Blake3 => 32,
+        }
+    }
+
+    /// Hash `data`
+    pub fn oid<A: AsRef<[u8]>>(&self, data: A) -> Oid {
+        let data = data.as_ref();
+        match *self {
+            HashAlg::Sha512 => {
+                let mut key = [0u8;::sodalite::HASH_LEN];
+                ::sodalite::hash(&mut key, data);
+                Oid::from_bytes(&key[..])
+            },
+            HashAlg::Sha256 => {
+                use sha2::Digest;
+                Oid::from_bytes(::sha2::Sha256::digest(data).as_slice())
+            },
+            HashAlg::Blake3 => {
+                Oid::from_bytes(&::blake3::hash(data).as_bytes()[..])
+            },
+        }
+    }
+}
+
+impl Default for HashAlg {
+    fn default() -> Self {
+        HashAlg::Sha512
+    }
+}
+
+impl fmt::Display for HashAlg {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.name())
+    }
+}
+
+/// How `Store::put_blob` arranges the list of pieces making up a blob
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum BlobLayout {
+    /// The concatenated list of piece oids is itself split by the chunker, recursively, forming
+    /// `Kind::Blob` objects. Tree shape & depth depend on the content of the oids.
+    Chunked,
+
+    /// A balanced tree of `Kind::Index` objects, each holding at most `Config::fanout` entries
+    /// and recording its depth above the pieces.
+    Tree,
+
+    /// Like `Tree`, but the entries of each level are grouped into index objects at boundaries
+    /// chosen by the oid of each entry (bounded by `Config::fanout`) instead of every `fanout`
+    /// entries. Inserting or removing pieces then only changes the index objects on the path to
+    /// them, so an edit near the start of a large blob rewrites O(log n) index objects & the rest
+    /// dedup against the previous version.
+    CdcTree,
+}
+
+impl BlobLayout {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BlobLayout::Chunked => "chunked",
+            BlobLayout::Tree => "tree",
+            BlobLayout::CdcTree => "cdc-tree",
+        }
+    }
+
+    pub fn from_name(n: &str) -> Option<Self> {
+        match n {
+            "chunked" => Some(BlobLayout::Chunked),
+            "tree" => Some(BlobLayout::Tree),
+            "cdc-tree" => Some(BlobLayout::CdcTree),
+            _ => None,
+        }
+    }
+}
+
+impl Default for BlobLayout {
+    fn default() -> Self {
+        BlobLayout::CdcTree
+    }
+}
+
+impl fmt::Display for BlobLayout {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.name())
+    }
+}
+
+/// Default maximum number of entries in a `Kind::Index` object. With 64 byte oids this makes
+/// index objects about the size of the default average piece.
+const DEFAULT_FANOUT: usize = 128;
+
+/// Configuration of a `Store`
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub struct Config {
+    pub hash: HashAlg,
+    pub chunker: ChunkerKind,
+    pub piece_size: PieceSize,
+    pub blob_layout: BlobLayout,
+    /// Maximum entries in an index object (`BlobLayout::Tree` & `BlobLayout::CdcTree`)
+    pub fanout: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            hash: HashAlg::default(),
+            chunker: ChunkerKind::default(),
+            piece_size: PieceSize::default(),
+            blob_layout: BlobLayout::default(),
+            fanout: DEFAULT_FANOUT,
+        }
+    }
+}
+
+impl Config {
+    /// The configuration implied by an empty `config` file, and that of stores made before there
+    /// was one
+    pub(crate) fn legacy() -> Self {
+        Config {
+            hash: HashAlg::Sha512,
+            chunker: ChunkerKind::Bup,
+            piece_size: PieceSize::unbounded(),
+            blob_layout: BlobLayout::Chunked,
+            fanout: DEFAULT_FANOUT,
+        }
+    }
+
+    /// Check that the combination of values is usable
+    pub fn check(&self) -> io::Result<()> {
+        if self.fanout < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("fanout {} must be at least 2", self.fanout)));
+        }
+        self.piece_size.check(self.chunker)
+    }
+
+    /// Construct a new splitter for data being stored
+    pub fn new_chunker(&self) -> ::chunk::Chunker {
+        self.chunker.chunker(&self.piece_size)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("hash {}\nchunker {}\npiece-min {}\npiece-avg {}\npiece-max {}\nblob-layout {}\nfanout {}\n",
+                self.hash, self.chunker,
+                self.piece_size.min, self.piece_size.avg, self.piece_size.max,
+                self.blob_layout, self.fanout).into_bytes()
+    }
+
+    pub fn from_bytes(d: &[u8]) -> io::Result<Self> {
+        let d = ::std::str::from_utf8(d).map_err(invalid_data)?;
+        let mut c = Config::legacy();
+
+        for line in d.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut s = line.splitn(2, ' ');
+            let k = s.next().unwrap();
+            let v = s.next().ok_or_else(|| invalid_data(format!("config line {:?} has no value", line)))?.trim();
+            match k {
+                "hash" => c.hash = HashAlg::from_name(v)
+                    .ok_or_else(|| invalid_data(format!("unknown hash algorithm {:?}", v)))?,
+                "chunker" => c.chunker = ChunkerKind::from_name(v)
+                    .ok_or_else(|| invalid_data(format!("unknown chunker {:?}", v)))?,
+                "piece-min" => c.piece_size.min = v.parse().map_err(invalid_data)?,
+                "piece-avg" => c.piece_size.avg = v.parse().map_err(invalid_data)?,
+                "piece-max" => c.piece_size.max = v.parse().map_err(invalid_data)?,
+                "blob-layout" => c.blob_layout = BlobLayout::from_name(v)
+                    .ok_or_else(|| invalid_data(format!("unknown blob layout {:?}", v)))?,
+                "fanout" => c.fanout = v.parse().map_err(invalid_data)?,
+                _ => return Err(invalid_data(format!("unknown config field {:?}", k))),
+            }
+        }
+
+        c.check().map_err(|e| invalid_data(format!("invalid config: {}", e)))?;
+        Ok(c)
+    }
+
+    /// Load the config stored in `d`, if any.
+    pub(crate) fn load(d: &Dir) -> io::Result<Option<Self>> {
+        match fs::read_file(d, "config")? {
+            Some(b) => Ok(Some(Self::from_bytes(&b)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn save(&self, d: &Dir) -> io::Result<()> {
+        fs::replace_file(d, "config", &self.to_bytes(), 0o666)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config,HashAlg,BlobLayout};
+    use chunk::{ChunkerKind,PieceSize};
+
+    #[test]
+    fn config_round_trip() {
+        for h in &[HashAlg::Sha512, HashAlg::Sha256, HashAlg::Blake3] {
+            let c = Config { hash: *h, ..Config::default() };
+            assert_eq!(Config::from_bytes(&c.to_bytes()).unwrap(), c);
+        }
+    }
+
+    #[test]
+    fn config_round_trip_chunker() {
+        for k in ChunkerKind::all() {
+            let c = Config { chunker: *k, ..Config::default() };
+            assert_eq!(Config::from_bytes(&c.to_bytes()).unwrap(), c);
+        }
+    }
+
+    #[test]
+    fn config_layout() {
+        for l in &[BlobLayout::Chunked, BlobLayout::Tree, BlobLayout::CdcTree] {
+            let c = Config { blob_layout: *l, fanout: 7, ..Config::default() };
+            assert_eq!(Config::from_bytes(&c.to_bytes()).unwrap(), c);
+        }
+        assert!(Config::from_bytes(b"fanout 1\n").is_err());
+    }
+
+    #[test]
+    fn config_empty_is_legacy() {
+        assert_eq!(Config::from_bytes(b"").unwrap(), Config::legacy());
+    }
+
+    #[test]
+    fn config_piece_size() {
+        let c = Config::from_bytes(b"chunker gear\npiece-min 0\npiece-avg 4096\npiece-max 0\n").unwrap();
+        assert_eq!(c.piece_size, PieceSize { min: 0, avg: 4096, max: 0 });
+        assert_eq!(Config::from_bytes(&c.to_bytes()).unwrap(), c);
+
+        assert!(Config::from_bytes(b"chunker bup\npiece-avg 4096\n").is_err());
+    }
+
+    #[test]
+    fn oid_len() {
+        for h in &[HashAlg::Sha512, HashAlg::Sha256, HashAlg::Blake3] {
+            assert_eq!(h.oid(b"x").as_bytes().len(), h.oid_len());
+        }
+    }
+}
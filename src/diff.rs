@@ -0,0 +1,132 @@
+//! Comparing two stored trees
+//!
+//! The trees are walked together in name order. Subtrees with the same oid are identical & are
+//! not looked into, so the cost depends on how much changed rather than on the size of the trees.
+use std::cmp::Ordering;
+use std::io;
+
+use tree::{Tree,TreeEntry,EntryKind};
+use Store;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    /// The content (or type) changed
+    Modified,
+    /// Only the metadata (permissions, owner, times or extended attributes) changed
+    Metadata,
+}
+
+impl ChangeKind {
+    /// One character describing the change
+    pub fn symbol(&self) -> char {
+        match *self {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Metadata => 'U',
+        }
+    }
+}
+
+/// A difference between two trees. Added or removed directories are reported alone, not with
+/// everything below them.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Change {
+    /// `/` separated, relative to the trees compared
+    pub path: Vec<u8>,
+    pub kind: ChangeKind,
+    /// The type of the entry (in the newer tree, unless it was removed)
+    pub entry: EntryKind,
+}
+
+/// Whether the metadata worth reporting is the same. Change times & inodes differ whenever
+/// files are restored or copied, so they are ignored.
+fn same_meta(a: &TreeEntry, b: &TreeEntry) -> bool {
+    a.mode == b.mode && a.uid == b.uid && a.gid == b.gid && a.user == b.user && a.group == b.group &&
+        a.mtime == b.mtime && a.xattrs == b.xattrs
+}
+
+fn same_content(a: &TreeEntry, b: &TreeEntry) -> bool {
+    a.kind == b.kind && a.oid == b.oid && a.size == b.size && a.target == b.target && a.rdev == b.rdev
+}
+
+fn join(base: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut p = base.to_owned();
+    if !p.is_empty() {
+        p.push(b'/');
+    }
+    p.extend(name);
+    p
+}
+
+struct Diff<'a> {
+    store: &'a Store,
+    changes: Vec<Change>,
+}
+
+impl<'a> Diff<'a> {
+    fn tree(&self, e: &TreeEntry) -> io::Result<Tree> {
+        let oid = e.oid.as_ref().unwrap();
+        self.store.get_tree(oid)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("missing tree {}", oid)))
+    }
+
+    fn push(&mut self, path: Vec<u8>, kind: ChangeKind, entry: EntryKind) {
+        self.changes.push(Change { path: path, kind: kind, entry: entry });
+    }
+
+    fn entry(&mut self, path: Vec<u8>, a: &TreeEntry, b: &TreeEntry) -> io::Result<()> {
+        if a.kind == EntryKind::Dir && b.kind == EntryKind::Dir {
+            if !same_meta(a, b) {
+                self.push(path.clone(), ChangeKind::Metadata, b.kind);
+            }
+            if a.oid != b.oid {
+                let (ta, tb) = (self.tree(a)?, self.tree(b)?);
+                self.dir(&path, &ta, &tb)?;
+            }
+        } else if !same_content(a, b) {
+            self.push(path, ChangeKind::Modified, b.kind);
+        } else if !same_meta(a, b) {
+            self.push(path, ChangeKind::Metadata, b.kind);
+        }
+        Ok(())
+    }
+
+    fn dir(&mut self, path: &[u8], a: &Tree, b: &Tree) -> io::Result<()> {
+        let (mut i, mut j) = (0, 0);
+        while i < a.entries.len() || j < b.entries.len() {
+            let ord = match (a.entries.get(i), b.entries.get(j)) {
+                (Some(x), Some(y)) => x.name.cmp(&y.name),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match ord {
+                Ordering::Less => {
+                    let e = &a.entries[i];
+                    self.push(join(path, &e.name), ChangeKind::Removed, e.kind);
+                    i += 1;
+                },
+                Ordering::Greater => {
+                    let e = &b.entries[j];
+                    self.push(join(path, &e.name), ChangeKind::Added, e.kind);
+                    j += 1;
+                },
+                Ordering::Equal => {
+                    let (x, y) = (&a.entries[i], &b.entries[j]);
+                    self.entry(join(path, &x.name), x, y)?;
+                    i += 1;
+                    j += 1;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn diff(store: &Store, a: &TreeEntry, b: &TreeEntry) -> io::Result<Vec<Change>> {
+    let mut d = Diff { store: store, changes: vec![] };
+    d.entry(vec![], a, b)?;
+    Ok(d.changes)
+}
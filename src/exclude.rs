@@ -0,0 +1,211 @@
+//! Exclude rules for the backup walk, in the syntax of `.gitignore`
+//!
+//! Each rule is a glob matched against the path of an entry relative to the backup source, with
+//! `/` separators. `*` & `?` match within one component, `[...]` matches a class of characters
+//! and `**` matches any number of components. A rule containing a `/` (other than at its end) is
+//! anchored to the source; otherwise it matches the name of the entry at any depth. A trailing
+//! `/` restricts a rule to directories, and a leading `!` makes it re-include what earlier rules
+//! excluded. The last rule that matches an entry decides. Blank lines & lines starting with `#`
+//! are ignored.
+//!
+//! As with git, nothing below an excluded directory is looked at, so it can't be re-included.
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+struct Rule {
+    glob: Vec<u8>,
+    negate: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let mut l = line.trim_end_matches(|c| c == ' ' || c == '\r').as_bytes();
+        if l.is_empty() || l[0] == b'#' {
+            return None;
+        }
+
+        let negate = l[0] == b'!';
+        if negate {
+            l = &l[1..];
+        }
+        let dir_only = l.last() == Some(&b'/');
+        if dir_only {
+            l = &l[..l.len() - 1];
+        }
+        let anchored = l.contains(&b'/');
+        if l.first() == Some(&b'/') {
+            l = &l[1..];
+        }
+        if l.is_empty() {
+            return None;
+        }
+
+        Some(Rule { glob: l.to_owned(), negate: negate, dir_only: dir_only, anchored: anchored })
+    }
+
+    fn matches(&self, path: &[u8], dir: bool) -> bool {
+        if self.dir_only && !dir {
+            return false;
+        }
+        if self.anchored {
+            glob(&self.glob, path)
+        } else {
+            let name = match path.iter().rposition(|&b| b == b'/') {
+                Some(i) => &path[i + 1..],
+                None => path,
+            };
+            glob(&self.glob, name)
+        }
+    }
+}
+
+/// Match a `[...]` class at the start of `p` against `c`. Returns whether it matched & the length
+/// of the class, or `None` if the class is not terminated (so the `[` is literal).
+fn class(p: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = i < p.len() && (p[i] == b'!' || p[i] == b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut found = false;
+    let mut first = true;
+    while i < p.len() {
+        if p[i] == b']' && !first {
+            return Some((found != negate && c != b'/', i + 1));
+        }
+        first = false;
+
+        let mut lo = p[i];
+        if lo == b'\\' && i + 1 < p.len() {
+            i += 1;
+            lo = p[i];
+        }
+        let mut hi = lo;
+        if i + 2 < p.len() && p[i + 1] == b'-' && p[i + 2] != b']' {
+            hi = p[i + 2];
+            i += 2;
+        }
+        if lo <= c && c <= hi {
+            found = true;
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Whether the glob `p` matches all of `s`
+fn glob(p: &[u8], s: &[u8]) -> bool {
+    if p.is_empty() {
+        return s.is_empty();
+    }
+
+    if p.starts_with(b"**") && (p.len() == 2 || p[2] == b'/') {
+        if p.len() == 2 {
+            return true;
+        }
+        // zero or more whole components
+        let rest = &p[3..];
+        if glob(rest, s) {
+            return true;
+        }
+        return s.iter().enumerate().any(|(i, &b)| b == b'/' && glob(rest, &s[i + 1..]));
+    }
+
+    match p[0] {
+        b'*' => {
+            for i in 0..(s.len() + 1) {
+                if glob(&p[1..], &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        },
+        b'?' => !s.is_empty() && s[0] != b'/' && glob(&p[1..], &s[1..]),
+        b'[' if !s.is_empty() => match class(p, s[0]) {
+            Some((m, l)) => m && glob(&p[l..], &s[1..]),
+            None => s[0] == b'[' && glob(&p[1..], &s[1..]),
+        },
+        b'\\' if p.len() > 1 => !s.is_empty() && s[0] == p[1] && glob(&p[2..], &s[1..]),
+        c => !s.is_empty() && s[0] == c && glob(&p[1..], &s[1..]),
+    }
+}
+
+/// A list of exclude rules
+#[derive(Debug,Clone,Default)]
+pub struct Exclude {
+    rules: Vec<Rule>,
+}
+
+impl Exclude {
+    /// Parse rules, one per item (as lines of a `.gitignore` file)
+    pub fn new<S: AsRef<str>>(rules: &[S]) -> Self {
+        Exclude {
+            rules: rules.iter().filter_map(|l| Rule::parse(l.as_ref())).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the entry at `path` (relative to the source, `/` separated) is excluded
+    pub fn is_excluded(&self, path: &[u8], dir: bool) -> bool {
+        match self.rules.iter().rev().find(|r| r.matches(path, dir)) {
+            Some(r) => !r.negate,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Exclude,glob};
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"*.o", b"a.o"));
+        assert!(!glob(b"*.o", b"a/b.o"));
+        assert!(glob(b"a?c", b"abc"));
+        assert!(!glob(b"a?c", b"a/c"));
+        assert!(glob(b"[a-c]x", b"bx"));
+        assert!(!glob(b"[!a-c]x", b"bx"));
+        assert!(glob(b"[]]", b"]"));
+        assert!(glob(b"[x", b"[x"));
+        assert!(glob(b"**/foo", b"foo"));
+        assert!(glob(b"**/foo", b"a/b/foo"));
+        assert!(glob(b"a/**", b"a/b/c"));
+        assert!(glob(b"a/**/b", b"a/b"));
+        assert!(glob(b"a/**/b", b"a/x/y/b"));
+        assert!(!glob(b"a/**/b", b"a/x/yb"));
+        assert!(glob(b"\\*", b"*"));
+        assert!(!glob(b"\\*", b"x"));
+    }
+
+    #[test]
+    fn rules() {
+        let e = Exclude::new(&[
+            "# comment",
+            "",
+            "node_modules/",
+            "*.log",
+            "!keep.log",
+            "/build",
+            "doc/*.tmp",
+        ]);
+        assert!(e.is_excluded(b"node_modules", true));
+        assert!(e.is_excluded(b"a/b/node_modules", true));
+        assert!(!e.is_excluded(b"node_modules", false));
+        assert!(e.is_excluded(b"x/y.log", false));
+        assert!(!e.is_excluded(b"x/keep.log", false));
+        assert!(e.is_excluded(b"build", true));
+        assert!(!e.is_excluded(b"src/build", true));
+        assert!(e.is_excluded(b"doc/a.tmp", false));
+        assert!(!e.is_excluded(b"x/doc/a.tmp", false));
+        assert!(!e.is_excluded(b"src/main.rs", false));
+    }
+}
@@ -1,4 +1,7 @@
+use ::std::error::Error;
 use ::std::ffi::CString;
+use ::hex::ToHex;
+use Oid;
 //use ::openat::Dir;
 
 fn to_cstr<P: ::openat::AsPath>(path: P) -> ::std::io::Result<P::Buffer> {
@@ -89,8 +92,87 @@ struct TempFile {
 }
 */
 
+/// An `InvalidData` error, for malformed store contents
+pub(crate) fn invalid_data<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> ::std::io::Error {
+    ::std::io::Error::new(::std::io::ErrorKind::InvalidData, e)
+}
+
+/// Read the entire contents of `name`, returning `None` if it does not exist.
+pub fn read_file(d: &::openat::Dir, name: &str) -> ::std::io::Result<Option<Vec<u8>>>
+{
+    use ::std::io::Read;
+    let mut f = match d.open_file(name) {
+        Ok(f) => f,
+        Err(e) => return match e.kind() {
+            ::std::io::ErrorKind::NotFound => Ok(None),
+            _ => Err(e),
+        },
+    };
+    let mut b = vec![];
+    f.read_to_end(&mut b)?;
+    Ok(Some(b))
+}
+
+/// Atomically replace (or create) `name` with a file containing `data`.
+///
+/// The new contents are written to a dot-prefixed temporary file in the same directory & then
+/// renamed over `name`.
+pub fn replace_file(d: &::openat::Dir, name: &str, data: &[u8], mode: u32) -> ::std::io::Result<()>
+{
+    use ::std::io::Write;
+    let tmp = format!(".new-{}", name);
+    let r = d.create_file(tmp.as_str(), mode)
+        .and_then(|mut f| { f.write_all(data)?; f.sync_all() })
+        .and_then(|_| ::openat::rename(d, tmp.as_str(), d, name));
+    if r.is_err() {
+        let _ = d.remove_file(tmp.as_str());
+    }
+    r
+}
+
+/// A directory of refs to objects: one file per object, named by (and containing) its hex oid
+pub struct RefDir {
+    dir: ::openat::Dir,
+}
+
+impl RefDir {
+    /// Open `name` in `base`, creating it if needed
+    pub(crate) fn with_parent(base: &::openat::Dir, name: &str) -> ::std::io::Result<Self> {
+        Ok(RefDir {
+            dir: base.create_dir_open(name)?,
+        })
+    }
+
+    /// Oids of all the refs, in no particular order
+    pub fn list(&self) -> ::std::io::Result<Vec<Oid>> {
+        let mut r = vec![];
+        for e in self.dir.list_dir(".")? {
+            let e = e?;
+            match e.file_name().to_str() {
+                Some(n) if !n.starts_with('.') => r.push(Oid::from_hex(n).map_err(invalid_data)?),
+                _ => continue,
+            }
+        }
+        Ok(r)
+    }
+
+    pub fn add(&self, oid: &Oid) -> ::std::io::Result<()> {
+        let n = oid.as_bytes().to_hex();
+        replace_file(&self.dir, &n, format!("{}\n", n).as_bytes(), 0o666)
+    }
+
+    /// Remove the ref to `oid`. Returns false if there was none.
+    pub fn remove(&self, oid: &Oid) -> ::std::io::Result<bool> {
+        match self.dir.remove_file(oid.as_bytes().to_hex().as_str()) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
 // -> impl ::openat::AsPath
-fn tempdir_name<P: ::openat::AsPath>(prefix: P) -> CString
+pub fn tempdir_name<P: ::openat::AsPath>(prefix: P) -> CString
 {
     use ::rand::Rng;
     // FIXME: ideally, we'd avoid converting to cstring & then back again. Can optimize this.
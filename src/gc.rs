@@ -0,0 +1,295 @@
+//! Removing objects that no kept snapshot refers to
+//!
+//! Everything reachable from the snapshots in `snapshots/` (or `GcOptions::snapshots`) & the refs
+//! in `roots/` is marked, then every object file that was not marked is removed. Blobs stored with
+//! `put_blob` are only kept if they are added to `roots/`.
+//!
+//! gc holds the store lock exclusively, and a backup holds it shared for as long as it runs, so the
+//! objects of a backup that has not written its snapshot yet are never removed. Other writers are
+//! covered by the grace period: objects modified recently (storing an object that already exists
+//! counts) are kept, along with everything they refer to.
+use std::collections::HashSet;
+use std::io;
+use std::io::{Cursor,Read};
+use openat::Dir;
+
+use fs::RefDir;
+use tree::{TreeEntry,EntryKind,XattrValue};
+use {Store,Oid,Kind,read_u64,read_index_entry,read_piece_entry};
+
+#[derive(Debug,Clone)]
+pub struct GcOptions {
+    /// Only report what would be removed
+    pub dry_run: bool,
+    /// Keep unreferenced objects modified less than this many seconds ago
+    pub grace_secs: u64,
+    /// Keep what these snapshots use instead of those in `snapshots/`, to see what removing
+    /// snapshot refs would free before doing so
+    pub snapshots: Option<Vec<Oid>>,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            dry_run: false,
+            grace_secs: 60 * 60,
+            snapshots: None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct GcSummary {
+    /// Objects reachable from a kept snapshot or a root
+    pub kept: u64,
+    /// Objects not reachable, but too recent to remove or reachable from one that is
+    pub recent: u64,
+    pub removed: u64,
+    /// Size of the object files removed
+    pub bytes_removed: u64,
+}
+
+fn missing(oid: &Oid) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("missing object {}, not removing anything", oid))
+}
+
+struct Gc<'a> {
+    store: &'a Store,
+    opts: &'a GcOptions,
+    marked: HashSet<Oid>,
+    /// Objects marked only because a recent object refers to them
+    recent: HashSet<Oid>,
+    /// Set while marking from recent objects, so what they reach goes in `recent`
+    marking_recent: bool,
+    cutoff: i64,
+    summary: GcSummary,
+}
+
+impl<'a> Gc<'a> {
+    /// Returns false if `oid` was already marked
+    fn mark(&mut self, oid: &Oid) -> bool {
+        if self.marked.contains(oid) {
+            return false;
+        }
+        if self.marking_recent {
+            self.recent.insert(oid.clone())
+        } else {
+            self.marked.insert(oid.clone())
+        }
+    }
+
+    /// Mark `oid` & everything it refers to, whatever its kind
+    fn object(&mut self, oid: &Oid) -> io::Result<()> {
+        let kind = match self.store.get(oid)? {
+            Some(o) => o.kind(),
+            None => return Err(missing(oid)),
+        };
+        match kind {
+            Kind::Snapshot => self.snapshot(oid),
+            Kind::Tree => self.tree(oid),
+            _ => self.blob(oid),
+        }
+    }
+
+    /// Oids of the objects in `d`, which is `level` directories below the base, modified since
+    /// the cutoff
+    fn recent_objects(&self, d: &Dir, prefix: &str, level: usize, r: &mut Vec<Oid>) -> io::Result<()> {
+        for e in d.list_dir(".")? {
+            let n = match e?.file_name().to_str() {
+                Some(n) if n.bytes().all(|b| b.is_ascii_hexdigit()) => n.to_owned(),
+                _ => continue,
+            };
+            let m = match d.metadata(n.as_str()) {
+                Ok(v) => v,
+                // removed by someone else meanwhile
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if level < self.store.split_ct() {
+                if n.len() == 2 && m.is_dir() {
+                    self.recent_objects(&d.sub_dir(n.as_str())?, &format!("{}{}", prefix, n), level + 1, r)?;
+                }
+            } else if m.stat().st_mtime as i64 > self.cutoff {
+                if let Ok(oid) = Oid::from_hex(&format!("{}{}", prefix, n)) {
+                    r.push(oid);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self, oid: &Oid) -> io::Result<()> {
+        if !self.mark(oid) {
+            return Ok(());
+        }
+        let s = self.store.get_snapshot(oid)?.ok_or_else(|| missing(oid))?;
+        if let Some(ref r) = s.root {
+            self.xattrs(r)?;
+        }
+        self.tree(&s.tree)
+    }
+
+    fn xattrs(&mut self, e: &TreeEntry) -> io::Result<()> {
+        for x in &e.xattrs {
+            if let XattrValue::Blob(ref o) = x.value {
+                self.blob(o)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tree(&mut self, oid: &Oid) -> io::Result<()> {
+        if !self.mark(oid) {
+            return Ok(());
+        }
+        let t = self.store.get_tree(oid)?.ok_or_else(|| missing(oid))?;
+        for e in &t.entries {
+            match (e.kind, e.oid.as_ref()) {
+                (EntryKind::File, Some(o)) => self.blob(o)?,
+                (EntryKind::Dir, Some(o)) => self.tree(o)?,
+                _ => {},
+            }
+            self.xattrs(e)?;
+        }
+        Ok(())
+    }
+
+    fn blob(&mut self, oid: &Oid) -> io::Result<()> {
+        if !self.mark(oid) {
+            return Ok(());
+        }
+        let mut o = self.store.get(oid)?.ok_or_else(|| missing(oid))?;
+        match o.kind() {
+            Kind::Piece => Ok(()),
+            Kind::Index => {
+                let depth = read_u64(&mut o)?;
+                let oid_len = self.store.config().hash.oid_len();
+                while let Some(e) = read_index_entry(&mut o, oid_len)? {
+                    if ::blob::is_hole(&e.oid) {
+                        continue;
+                    }
+                    if depth > 1 {
+                        self.blob(&e.oid)?;
+                    } else {
+                        // a piece, no need to read it
+                        self.mark(&e.oid);
+                    }
+                }
+                Ok(())
+            },
+            Kind::Blob => self.legacy_blob(o),
+            k => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("object {} is a {:?}, expected a blob", oid, k))),
+        }
+    }
+
+    /// The pieces of a `Kind::Blob` (without its kind), which may themselves hold another list
+    fn legacy_blob<R: Read>(&mut self, mut o: R) -> io::Result<()> {
+        let sub_kind = Kind::read_from(&mut o)?;
+        let mut data = vec![];
+        while let Some(p) = read_piece_entry(&mut o, self.store.config().hash.oid_len())? {
+            self.mark(&p.oid);
+            if sub_kind == Kind::Blob {
+                data.extend(self.store.get(&p.oid)?.ok_or_else(|| missing(&p.oid))?.as_ref());
+            }
+        }
+        if sub_kind == Kind::Blob {
+            self.legacy_blob(Cursor::new(data))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the unmarked objects in `d`, which is `level` directories below the base
+    fn sweep(&mut self, d: &Dir, prefix: &str, level: usize) -> io::Result<()> {
+        let mut names = vec![];
+        for e in d.list_dir(".")? {
+            match e?.file_name().to_str() {
+                // skips temporary files, which start with `.`
+                Some(n) if n.bytes().all(|b| b.is_ascii_hexdigit()) => names.push(n.to_owned()),
+                _ => {},
+            }
+        }
+
+        for n in names {
+            let m = match d.metadata(n.as_str()) {
+                Ok(v) => v,
+                // removed by someone else meanwhile
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if level < self.store.split_ct() {
+                if n.len() == 2 && m.is_dir() {
+                    self.sweep(&d.sub_dir(n.as_str())?, &format!("{}{}", prefix, n), level + 1)?;
+                }
+                continue;
+            }
+
+            let oid = match Oid::from_hex(&format!("{}{}", prefix, n)) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if self.marked.contains(&oid) {
+                self.summary.kept += 1;
+            } else if self.recent.contains(&oid) || m.stat().st_mtime as i64 > self.cutoff {
+                self.summary.recent += 1;
+            } else {
+                if !self.opts.dry_run {
+                    d.remove_file(n.as_str())?;
+                }
+                self.summary.removed += 1;
+                self.summary.bytes_removed += m.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn gc(store: &Store, opts: &GcOptions) -> io::Result<GcSummary> {
+    let _lock = store.lock(true).map_err(|e| {
+        if e.kind() == io::ErrorKind::WouldBlock {
+            io::Error::new(e.kind(), "the store is locked by a running backup")
+        } else {
+            e
+        }
+    })?;
+    let now = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs()).unwrap_or(0);
+    let mut g = Gc {
+        store: store,
+        opts: opts,
+        marked: HashSet::new(),
+        recent: HashSet::new(),
+        marking_recent: false,
+        cutoff: now as i64 - opts.grace_secs as i64,
+        summary: GcSummary::default(),
+    };
+
+    let snapshots = match opts.snapshots {
+        Some(ref v) => v.clone(),
+        None => store.snapshots()?.list()?,
+    };
+    for oid in snapshots {
+        g.snapshot(&oid)?;
+    }
+    for oid in store.roots()?.list()? {
+        g.object(&oid)?;
+    }
+
+    // a recent object may refer to old ones without having rewritten them (pieces shared with
+    // another blob, say), so those are kept too
+    let mut recent = vec![];
+    g.recent_objects(store.dir(), "", 0, &mut recent)?;
+    g.marking_recent = true;
+    for oid in recent {
+        if !g.marked.contains(&oid) {
+            g.object(&oid)?;
+        }
+    }
+
+    g.sweep(store.dir(), "", 0)?;
+    Ok(g.summary)
+}
+
+/// The `roots/` area of a `Store`: objects kept by gc, like `put_blob` blobs, that no snapshot
+/// refers to
+pub type Roots = RefDir;
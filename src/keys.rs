@@ -0,0 +1,346 @@
+//! Repository key management
+//!
+//! A store has a single randomly generated master key. The master key is never written to disk in
+//! the clear. Instead, each entry in the `keys/` directory of the store contains a copy of the
+//! master key wrapped (via `sodalite::secretbox`) by a key derived from a passphrase with scrypt.
+//!
+//! Adding another passphrase only requires unlocking the master key with an existing one.
+//! Removing a passphrase only removes its key file, so objects protected by the master key do not
+//! need to be rewritten when a passphrase is revoked.
+//!
+//! Key files are small text files of `name value` lines:
+//!
+//! ```text
+//! kdf scrypt
+//! log-n 15
+//! r 8
+//! p 1
+//! salt <hex>
+//! nonce <hex>
+//! wrapped <hex>
+//! ```
+use std::io;
+use hex::{FromHex,ToHex};
+use openat::Dir;
+use rand::Rng;
+
+use fs;
+use fs::invalid_data;
+use fs::DirVblockExt;
+
+const MASTER_KEY_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// `secretbox` requires this many leading zero bytes in the plaintext.
+const ZERO_LEN: usize = 32;
+/// `secretbox` produces this many leading zero bytes in the ciphertext.
+const BOX_ZERO_LEN: usize = 16;
+
+/// scrypt parameters used for newly created key files
+const DEFAULT_LOG_N: u8 = 15;
+const DEFAULT_R: u32 = 8;
+const DEFAULT_P: u32 = 1;
+
+/// The key that all other store secrets are derived from.
+pub struct MasterKey {
+    inner: [u8;MASTER_KEY_LEN],
+}
+
+impl MasterKey {
+    fn generate() -> io::Result<Self> {
+        let mut k = [0u8;MASTER_KEY_LEN];
+        ::rand::OsRng::new()?.fill_bytes(&mut k[..]);
+        Ok(MasterKey { inner: k })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner[..]
+    }
+}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        // XXX: the compiler is free to elide this.
+        for b in self.inner.iter_mut() {
+            *b = 0;
+        }
+    }
+}
+
+/// A single passphrase-wrapped copy of the master key, as stored in a key file.
+struct WrappedKey {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    wrapped: Vec<u8>,
+}
+
+impl WrappedKey {
+    fn derive(passphrase: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32) -> io::Result<[u8;32]> {
+        let params = ::scrypt::ScryptParams::new(log_n, r, p)
+            .map_err(|_| invalid_data("invalid scrypt parameters"))?;
+        let mut k = [0u8;32];
+        ::scrypt::scrypt(passphrase, salt, &params, &mut k[..])
+            .map_err(|_| invalid_data("scrypt failed"))?;
+        Ok(k)
+    }
+
+    fn seal(master: &MasterKey, passphrase: &[u8]) -> io::Result<Self> {
+        let mut rng = ::rand::OsRng::new()?;
+        let mut salt = vec![0u8;SALT_LEN];
+        rng.fill_bytes(&mut salt[..]);
+        let mut nonce = [0u8;NONCE_LEN];
+        rng.fill_bytes(&mut nonce[..]);
+
+        let k = Self::derive(passphrase, &salt, DEFAULT_LOG_N, DEFAULT_R, DEFAULT_P)?;
+
+        let mut m = vec![0u8;ZERO_LEN + MASTER_KEY_LEN];
+        m[ZERO_LEN..].copy_from_slice(master.as_bytes());
+        let mut c = vec![0u8;m.len()];
+        ::sodalite::secretbox(&mut c, &m, &nonce, &k)
+            .map_err(|_| invalid_data("secretbox failed"))?;
+        for b in m.iter_mut() {
+            *b = 0;
+        }
+
+        Ok(WrappedKey {
+            log_n: DEFAULT_LOG_N,
+            r: DEFAULT_R,
+            p: DEFAULT_P,
+            salt: salt,
+            nonce: nonce[..].to_owned(),
+            wrapped: c[BOX_ZERO_LEN..].to_owned(),
+        })
+    }
+
+    /// Returns `None` if the passphrase does not unlock this key
+    fn open(&self, passphrase: &[u8]) -> io::Result<Option<MasterKey>> {
+        if self.nonce.len() != NONCE_LEN || self.wrapped.len() != ZERO_LEN - BOX_ZERO_LEN + MASTER_KEY_LEN {
+            return Err(invalid_data("key file has invalid lengths"));
+        }
+
+        let k = Self::derive(passphrase, &self.salt, self.log_n, self.r, self.p)?;
+        let mut nonce = [0u8;NONCE_LEN];
+        nonce.copy_from_slice(&self.nonce);
+
+        let mut c = vec![0u8;BOX_ZERO_LEN];
+        c.extend(&self.wrapped);
+        let mut m = vec![0u8;c.len()];
+        if ::sodalite::secretbox_open(&mut m, &c, &nonce, &k).is_err() {
+            return Ok(None);
+        }
+
+        let mut mk = [0u8;MASTER_KEY_LEN];
+        mk.copy_from_slice(&m[ZERO_LEN..]);
+        for b in m.iter_mut() {
+            *b = 0;
+        }
+        Ok(Some(MasterKey { inner: mk }))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        format!("kdf scrypt\nlog-n {}\nr {}\np {}\nsalt {}\nnonce {}\nwrapped {}\n",
+                self.log_n, self.r, self.p,
+                self.salt.to_hex(), self.nonce.to_hex(), self.wrapped.to_hex()).into_bytes()
+    }
+
+    fn from_bytes(d: &[u8]) -> io::Result<Self> {
+        let d = ::std::str::from_utf8(d).map_err(invalid_data)?;
+        let mut log_n = None;
+        let mut r = None;
+        let mut p = None;
+        let mut salt = None;
+        let mut nonce = None;
+        let mut wrapped = None;
+
+        for line in d.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut s = line.splitn(2, ' ');
+            let k = s.next().unwrap();
+            let v = s.next().ok_or_else(|| invalid_data(format!("key file line {:?} has no value", line)))?;
+            match k {
+                "kdf" => if v != "scrypt" {
+                    return Err(invalid_data(format!("unsupported kdf {:?}", v)));
+                },
+                "log-n" => log_n = Some(v.parse::<u8>().map_err(invalid_data)?),
+                "r" => r = Some(v.parse::<u32>().map_err(invalid_data)?),
+                "p" => p = Some(v.parse::<u32>().map_err(invalid_data)?),
+                "salt" => salt = Some(Vec::<u8>::from_hex(v).map_err(invalid_data)?),
+                "nonce" => nonce = Some(Vec::<u8>::from_hex(v).map_err(invalid_data)?),
+                "wrapped" => wrapped = Some(Vec::<u8>::from_hex(v).map_err(invalid_data)?),
+                _ => return Err(invalid_data(format!("unknown key file field {:?}", k))),
+            }
+        }
+
+        fn req<T>(v: Option<T>, n: &str) -> io::Result<T> {
+            v.ok_or_else(|| invalid_data(format!("key file is missing {:?}", n)))
+        }
+
+        Ok(WrappedKey {
+            log_n: req(log_n, "log-n")?,
+            r: req(r, "r")?,
+            p: req(p, "p")?,
+            salt: req(salt, "salt")?,
+            nonce: req(nonce, "nonce")?,
+            wrapped: req(wrapped, "wrapped")?,
+        })
+    }
+}
+
+/// The `keys/` area of a `Store`
+pub struct Keys {
+    dir: Dir,
+}
+
+impl Keys {
+    pub(crate) fn with_parent(base: &Dir) -> io::Result<Self> {
+        Ok(Keys {
+            dir: base.create_dir_open("keys")?,
+        })
+    }
+
+    fn check_name(name: &str) -> io::Result<()> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid key name {:?}", name)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Names of all the key files
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for e in self.dir.list_dir(".")? {
+            let e = e?;
+            let n = match e.file_name().to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            if n.starts_with('.') {
+                continue;
+            }
+            names.push(n.to_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> io::Result<WrappedKey> {
+        Self::check_name(name)?;
+        match fs::read_file(&self.dir, name)? {
+            Some(b) => WrappedKey::from_bytes(&b),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("key {:?} does not exist", name))),
+        }
+    }
+
+    fn write(&self, name: &str, wk: &WrappedKey) -> io::Result<()> {
+        Self::check_name(name)?;
+        fs::replace_file(&self.dir, name, &wk.to_bytes(), 0o600)
+    }
+
+    /// Key files that can't be read or parsed, with why
+    pub fn unreadable(&self) -> io::Result<Vec<(String, io::Error)>> {
+        let mut r = vec![];
+        for n in self.list()? {
+            if let Err(e) = self.read(&n) {
+                r.push((n, e));
+            }
+        }
+        Ok(r)
+    }
+
+    /// Try the passphrase against every key file, returning the master key from the first one it
+    /// unlocks.
+    ///
+    /// Key files that can't be read are skipped (see `unreadable`), so one damaged file doesn't
+    /// lock everyone out. If no key is unlocked, the error lists them.
+    pub fn unlock(&self, passphrase: &[u8]) -> io::Result<MasterKey> {
+        let names = self.list()?;
+        if names.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "store has no keys"));
+        }
+
+        let mut bad = vec![];
+        for n in names {
+            match self.read(&n).and_then(|wk| wk.open(passphrase)) {
+                Ok(Some(mk)) => return Ok(mk),
+                Ok(None) => {},
+                Err(e) => bad.push(format!("{}: {}", n, e)),
+            }
+        }
+
+        let mut msg = "passphrase does not match any key".to_owned();
+        if !bad.is_empty() {
+            msg.push_str(&format!(" (skipped unreadable key files: {})", bad.join(", ")));
+        }
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+    }
+
+    /// Add a new key file named `name` wrapping the master key with `new_passphrase`.
+    ///
+    /// If the store has no keys yet, a new master key is generated and `unlock_passphrase` is
+    /// ignored. Otherwise `unlock_passphrase` must unlock one of the existing keys.
+    pub fn add(&self, name: &str, unlock_passphrase: &[u8], new_passphrase: &[u8]) -> io::Result<()> {
+        Self::check_name(name)?;
+        let names = self.list()?;
+        if names.iter().any(|n| n == name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("key {:?} already exists", name)));
+        }
+
+        let mk = if names.is_empty() {
+            MasterKey::generate()?
+        } else {
+            self.unlock(unlock_passphrase)?
+        };
+
+        self.write(name, &WrappedKey::seal(&mk, new_passphrase)?)
+    }
+
+    /// Remove the key file named `name`. The last remaining key can not be removed, as doing so
+    /// would make the master key unrecoverable.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        Self::check_name(name)?;
+        let names = self.list()?;
+        if !names.iter().any(|n| n == name) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("key {:?} does not exist", name)));
+        }
+        if names.len() == 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "refusing to remove the last key"));
+        }
+
+        self.dir.remove_file(name)
+    }
+
+    /// Re-wrap the master key held in key file `name` with a new passphrase.
+    pub fn change_passphrase(&self, name: &str, old_passphrase: &[u8], new_passphrase: &[u8]) -> io::Result<()> {
+        let mk = match self.read(name)?.open(old_passphrase)? {
+            Some(v) => v,
+            None => return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                              format!("passphrase does not match key {:?}", name))),
+        };
+
+        self.write(name, &WrappedKey::seal(&mk, new_passphrase)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MasterKey,WrappedKey};
+
+    #[test]
+    fn wrap_round_trip() {
+        let mk = MasterKey::generate().unwrap();
+        let wk = WrappedKey::seal(&mk, b"hunter2").unwrap();
+        let wk = WrappedKey::from_bytes(&wk.to_bytes()).unwrap();
+        assert!(wk.open(b"hunter3").unwrap().is_none());
+        let mk2 = wk.open(b"hunter2").unwrap().unwrap();
+        assert_eq!(mk.as_bytes(), mk2.as_bytes());
+    }
+}
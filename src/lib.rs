@@ -4,20 +4,56 @@ extern crate hex;
 extern crate sodalite;
 extern crate hash_roll;
 extern crate byteorder;
+extern crate scrypt;
+extern crate sha2;
+extern crate blake3;
+extern crate libc;
+#[cfg(test)]
+extern crate tempdir;
 
 use byteorder::ByteOrder;
-use hash_roll::Split2;
 use std::ffi::{CString,CStr};
 use std::io::Seek;
 use hex::{FromHex,ToHex};
 
 mod fs;
+mod sys;
+mod config;
+mod chunk;
+mod keys;
+mod sign;
+mod blob;
+mod cache;
+mod exclude;
+mod tree;
+mod snapshot;
+mod backup;
+mod diff;
+mod prune;
+mod gc;
+mod restore;
+pub use config::{Config,HashAlg,BlobLayout};
+pub use chunk::{Chunker,ChunkerKind,PieceSize};
+pub use keys::{Keys,MasterKey};
+pub use sign::{Signatures,Signature,SigningKey,PublicKey};
+pub use blob::BlobWriter;
+pub use tree::{Tree,TreeEntry,EntryKind,Time,Xattr,XattrValue,Inode};
+pub use snapshot::{Snapshot,Snapshots,DateTime};
+pub use exclude::Exclude;
+pub use backup::{BackupOptions,BackupSummary,XattrFilter};
+pub use restore::{RestoreOptions,RestoreSummary,Existing};
+pub use diff::{Change,ChangeKind};
+pub use prune::{KeepPolicy,PruneEntry,PruneSummary};
+pub use gc::{GcOptions,GcSummary,Roots};
+pub use fs::RefDir;
 use std::io::Read;
 use fs::DirVblockExt;
 use std::io::Write;
 use std::io;
 use std::io::Cursor;
 use openat::{Dir,DirIter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64,Ordering};
 
 /// Contains `Object`s identified by an object-id (`Oid`). Objects all have a Kind and have zero or
 /// more bytes of data. `Oid`s are the hash of the `kind + data` of the object.
@@ -28,6 +64,11 @@ use openat::{Dir,DirIter};
 /// interpretation.
 ///
 /// `Blob`s contain a list of `Oid`s which refer to other `Blob`s or to `Pieces`.
+///
+/// `Index`es are the nodes of a balanced tree over a sequence of `Piece`s. See `put_blob`.
+///
+/// A `Tree` describes a directory (see `Tree`), and a `Snapshot` records the root tree of a backup
+/// (see `Snapshot`).
 /// 
 /// TODO: right now oids/keys are tied to the disk format, consider allowing oids/keys that are
 /// related by aren't the direct hash of the vblock files. For example, allowing the hash of an
@@ -41,6 +82,11 @@ use openat::{Dir,DirIter};
 pub struct Store {
     base: openat::Dir,
     objects: openat::Dir,
+    config: Config,
+    threads: usize,
+    read_ahead: usize,
+    /// shared with the handles made by `try_clone`
+    stored: Arc<AtomicU64>,
 }
 
 /// Data stored has a given kind which controls it's interpretation
@@ -54,10 +100,18 @@ pub enum Kind {
     /// bytes.
     Blob,
 
-    /// A single level of a filesystem tree.
+    /// A single level of a filesystem tree. See `Tree`.
     // XXX: consider multiple levels in 1.
     // XXX: consider how splitting of large trees is handled.
     Tree,
+
+    /// A node in a balanced tree of pieces. Contains its depth (`u64`, 1 when the entries refer to
+    /// `Piece`s, otherwise entries refer to `Index`es of depth one less) followed by entries of
+    /// the length (`u64`) of the data below the entry & the oid of the entry.
+    Index,
+
+    /// The root of a backup, see `Snapshot`.
+    Snapshot,
 }
 
 impl Kind {
@@ -66,6 +120,8 @@ impl Kind {
             Kind::Piece => 1,
             Kind::Blob =>  2,
             Kind::Tree  => 3,
+            Kind::Index => 4,
+            Kind::Snapshot => 5,
         }
     }
 
@@ -74,6 +130,8 @@ impl Kind {
             1 => Ok(Kind::Piece),
             2 => Ok(Kind::Blob),
             3 => Ok(Kind::Tree),
+            4 => Ok(Kind::Index),
+            5 => Ok(Kind::Snapshot),
             e => Err(io::Error::new(io::ErrorKind::InvalidData, format!("kind {:?} is invalid", e))),
         }
     }
@@ -109,7 +167,7 @@ impl Kind {
 // FIXME: we really want this to be both a series of bytes & a cstr.
 //  - CStr is used for file paths
 //  - bytes are used for file contents
-#[derive(Debug,Eq,PartialEq,Clone)]
+#[derive(Debug,Eq,PartialEq,Clone,Hash)]
 pub struct Oid {
     inner: Vec<u8>,
 }
@@ -129,12 +187,6 @@ impl Oid {
         }
     }
 
-    fn from_data<A: AsRef<[u8]>>(data: A) -> Self {
-        let mut key = [0u8;sodalite::HASH_LEN];
-        sodalite::hash(&mut key, data.as_ref());
-        Oid::from_bytes(&key[..])
-    }
-
     /// TODO: this is very Index like, see if we can make that usable.
     fn get_part(&self, index: usize) -> OidPart {
         OidPart { inner: CString::new([self.as_ref()[index]].to_hex()).unwrap() }
@@ -149,12 +201,19 @@ impl Oid {
         self.inner.as_ref()
     }
 
-    fn len_str() -> usize {
-        Self::len() * 2
+    /// Length in bytes. Determined by the `HashAlg` of the store the oid came from.
+    pub fn len(&self) -> usize {
+        self.inner.len()
     }
 
-    fn len() -> usize {
-        sodalite::HASH_LEN
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl ::std::fmt::Display for Oid {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "{}", self.inner.to_hex())
     }
 }
 
@@ -176,12 +235,68 @@ impl<'a> openat::AsPath for &'a OidPart {
 }
 
 impl Store {
+    /// Open the store in `d`, creating it with the default `Config` if it does not exist.
+    ///
+    /// A store made before the `config` file existed (an `objects` directory with no `config`)
+    /// gets `Config::legacy()` recorded, so it keeps generating the oids it always has.
     pub fn with_dir(d: openat::Dir) -> io::Result<Self> {
+        let c = match Config::load(&d)? {
+            Some(c) => c,
+            None => {
+                let c = match d.metadata("objects") {
+                    Ok(_) => Config::legacy(),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+                    Err(e) => return Err(e),
+                };
+                c.save(&d)?;
+                c
+            }
+        };
+
+        Self::with_dir_config_inner(d, c)
+    }
+
+    /// Create a store in `d` using `config`.
+    ///
+    /// If a store already exists in `d` its configuration must match `config`.
+    pub fn with_dir_config(d: openat::Dir, config: Config) -> io::Result<Self> {
+        config.check()?;
+        match Config::load(&d)? {
+            Some(c) => {
+                if c != config {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                              format!("store exists with a different config: {:?}", c)));
+                }
+            },
+            None => config.save(&d)?,
+        }
+
+        Self::with_dir_config_inner(d, config)
+    }
+
+    fn with_dir_config_inner(d: openat::Dir, config: Config) -> io::Result<Self> {
         let o = d.create_dir_open("objects")?;
 
         Ok(Store {
             base: d,
             objects: o,
+            config: config,
+            threads: 1,
+            read_ahead: 32,
+            stored: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Another handle on the same store, for threads that can't borrow this one. Bytes stored
+    /// through either handle count towards `stored_bytes` of both.
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(Store {
+            base: self.base.sub_dir(".")?,
+            objects: self.objects.sub_dir(".")?,
+            config: self.config.clone(),
+            threads: self.threads,
+            read_ahead: self.read_ahead,
+            stored: self.stored.clone(),
         })
     }
 
@@ -190,16 +305,98 @@ impl Store {
         Self::with_dir(d)
     }
 
+    /// Like `with_dir_config`, for the directory at `p`
+    pub fn with_path_config<P: openat::AsPath>(p: P, config: Config) -> io::Result<Self> {
+        let d = ::openat::Dir::open(p)?;
+        Self::with_dir_config(d, config)
+    }
+
     pub fn dir(&self) -> &::openat::Dir {
         &self.base
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Number of threads used to hash pieces when storing blobs & to fetch them when reading
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Hash pieces on `threads` threads when storing blobs, and fetch pieces on `threads` threads
+    /// when reading them. 1 (the default) does all the work on the calling thread. The oids
+    /// produced do not depend on this setting.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = ::std::cmp::max(threads, 1);
+    }
+
+    /// Number of pieces fetched ahead of the one being returned when reading blobs with more than
+    /// one thread
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
+
+    pub fn set_read_ahead(&mut self, pieces: usize) {
+        self.read_ahead = ::std::cmp::max(pieces, 1);
+    }
+
+    /// The passphrase-wrapped master keys of this store
+    pub fn keys(&self) -> io::Result<Keys> {
+        Keys::with_parent(&self.base)
+    }
+
+    /// Bytes of objects (including their kind) written through this handle that were not already
+    /// in the store
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored.load(Ordering::Relaxed)
+    }
+
+    /// Refs to the snapshots kept in this store
+    pub fn snapshots(&self) -> io::Result<Snapshots> {
+        Snapshots::with_parent(&self.base, "snapshots")
+    }
+
+    /// Signatures attached to objects & the keys trusted to make them
+    pub fn signatures(&self) -> io::Result<Signatures> {
+        Signatures::with_parent(&self.base)
+    }
+
+    /// Refs to objects that gc keeps even though no snapshot refers to them
+    pub fn roots(&self) -> io::Result<Roots> {
+        Roots::with_parent(&self.base, "roots")
+    }
+
+    /// Lock the store against gc (shared, waiting for a running gc) or lock out everything that
+    /// holds it (exclusive, failing with `WouldBlock` if it is held). Released when the returned
+    /// file is dropped.
+    pub(crate) fn lock(&self, exclusive: bool) -> io::Result<::std::fs::File> {
+        let f = match self.base.open_file("lock") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.base.create_file("lock", 0o666)?,
+            r => r?,
+        };
+        sys::flock(&f, exclusive, !exclusive)?;
+        Ok(f)
+    }
+
     fn split_ct(&self) -> usize
     {
         4
     }
 
+    fn check_oid(&self, key: &Oid) -> io::Result<()> {
+        if key.len() != self.config.hash.oid_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("oid {} has length {}, but this store uses {} ({} bytes)",
+                                              key, key.len(), self.config.hash, self.config.hash.oid_len())));
+        }
+        Ok(())
+    }
+
+    /// The directory `key` is stored in, which is created if it does not exist yet
     fn object_dir(&self, key: &Oid) -> io::Result<Dir> {
+        self.check_oid(key)?;
+
         // TODO: consider allowing configurable levels for key-splitting.
         let l = self.split_ct();
         let mut d = Vec::with_capacity(l);
@@ -213,6 +410,26 @@ impl Store {
         d[l-2].create_dir_open(&key.get_part(l-1))
     }
 
+    /// Like `object_dir`, but `None` if the directory does not exist, so looking objects up
+    /// leaves the store unchanged
+    fn existing_object_dir(&self, key: &Oid) -> io::Result<Option<Dir>> {
+        self.check_oid(key)?;
+
+        let mut d: Option<Dir> = None;
+        for i in 0..self.split_ct() {
+            let r = match d {
+                Some(ref p) => p.sub_dir(&key.get_part(i)),
+                None => self.base.sub_dir(&key.get_part(i)),
+            };
+            d = match r {
+                Ok(v) => Some(v),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(d)
+    }
+
     fn object_name(&self, key: &Oid) -> OidPart
     {
         key.get_part_rem(self.split_ct())
@@ -230,6 +447,32 @@ impl Store {
         o.commit()
     }
 
+    /// Store `data` (which includes the kind) under `oid`, which the caller has already
+    /// calculated. Nothing is written if the object already exists.
+    fn write_object(&self, oid: &Oid, data: &[u8]) -> io::Result<()>
+    {
+        let d = self.object_dir(oid)?;
+        let name = self.object_name(oid);
+        if let Ok(f) = d.open_file(&name) {
+            // a dedup hit is as good as a fresh write to gc's grace period. Best effort: objects
+            // written by another user can't be touched, and backups hold the store lock anyway.
+            let _ = sys::touch(&f);
+            return Ok(());
+        }
+
+        // write beside the final name so the rename can't cross filesystems
+        let t = fs::tempdir_name(".new-object.");
+        let mut f = d.create_file(t.as_ref(), 0o666)?;
+        let r = f.write_all(data).and_then(|_| ::openat::rename(&d, t.as_ref(), &d, &name));
+        if r.is_err() {
+            // don't leave a partial object behind (gc skips temporary files)
+            let _ = d.remove_file(t.as_ref());
+        }
+        r?;
+        self.stored.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub fn get_object(&self, key: &Oid) -> io::Result<Option<Vec<u8>>> {
         let mut v = match self.get(key)? {
             Some(x) => x,
@@ -248,22 +491,201 @@ impl Store {
         Object::from_oid(self, oid.clone())
     }
 
+    /// Whether an object is stored under `oid`, without reading it
+    pub fn contains(&self, oid: &Oid) -> io::Result<bool> {
+        let d = match self.existing_object_dir(oid)? {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        match d.metadata(&self.object_name(oid)) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     /// A blob is a list of pieces. That list is then also split into pieces (recursively)
     ///
     /// The Oid of a blob is the overall hash of the data, which simply contains the Oid of the
     /// top-level piece of the list of pieces.
     ///
-    /// TODO: avoid needing the entire blob in memory at once. Use a streaming style api here.
-    ///
     /// XXX:
     ///  - blob formating options: pieces could have markers, or blobs could have a bit in the
     ///    piece entry indicating further deref.
     ///    
     ///  - need a concrete model for how recursive blobs work. Ideally, we'd have a tree-like
     ///    setup, but specifics are needed
+    ///
+    /// With `BlobLayout::Tree` the pieces are instead arranged in a balanced tree of `Kind::Index`
+    /// objects with at most `Config::fanout` entries each. Each index records its depth & the
+    /// length of the data below each entry, so a reader can find the piece containing any offset
+    /// by loading one index per level. A blob consisting of a single piece is just that piece.
+    ///
+    /// `BlobLayout::CdcTree` has the same structure, but chooses where each index object ends
+    /// based on the oids of the entries (see `index_groups`).
+    ///
+    /// See `blob_writer` to store a blob without holding all of it in memory.
     pub fn put_blob<A: AsRef<[u8]>>(&self, data: A) -> io::Result<Oid>
     {
-        self.put_blob_inner(Kind::Piece, data)
+        match self.config.blob_layout {
+            BlobLayout::Chunked => self.put_blob_inner(Kind::Piece, data),
+            BlobLayout::Tree | BlobLayout::CdcTree => {
+                let mut w = self.blob_writer();
+                w.write_all(data.as_ref())?;
+                w.commit()
+            },
+        }
+    }
+
+    /// Store a blob incrementally. Data written to the returned writer is split & stored as it
+    /// arrives, using `threads` threads for hashing.
+    pub fn blob_writer<'a>(&'a self) -> BlobWriter<'a>
+    {
+        BlobWriter::new(self)
+    }
+
+    /// Build the levels of `Kind::Index` objects above `entries` (which refer to pieces),
+    /// returning the oid of the root.
+    fn put_index_tree(&self, mut entries: Vec<IndexEntry>) -> io::Result<Oid>
+    {
+        if entries.is_empty() {
+            return self.put_object(Kind::Piece, &[]);
+        }
+
+        let mut depth = 1;
+        // a lone hole still needs an index to describe it
+        while entries.len() > 1 || (depth == 1 && blob::is_hole(&entries[0].oid)) {
+            let mut next = Vec::with_capacity(entries.len() / self.config.fanout + 1);
+            for group in self.index_groups(&entries) {
+                next.push(self.put_index(depth, group)?);
+            }
+            entries = next;
+            depth += 1;
+        }
+
+        Ok(entries.pop().unwrap().oid)
+    }
+
+    /// Divide one level of entries into the groups that each become an index object.
+    ///
+    /// For `BlobLayout::CdcTree` a group ends after an entry when the low bits of its oid are zero
+    /// (giving groups averaging between a quarter & half of the fanout), when the group reaches the
+    /// fanout, or at the end of the level. Groups (other than the last) always have at least 2
+    /// entries, so each level is smaller than the one below it.
+    fn index_groups<'e>(&self, entries: &'e [IndexEntry]) -> Vec<&'e [IndexEntry]>
+    {
+        let fanout = self.config.fanout;
+        match self.config.blob_layout {
+            BlobLayout::CdcTree => {
+                let mut avg = 1;
+                while avg * 4 <= fanout {
+                    avg *= 2;
+                }
+                let mask = avg as u32 - 1;
+
+                let mut groups = vec![];
+                let mut start = 0;
+                for (i, e) in entries.iter().enumerate() {
+                    let n = i + 1 - start;
+                    let h = byteorder::LittleEndian::read_u32(&e.oid.as_bytes()[..4]);
+                    if n >= fanout || (n >= 2 && h & mask == 0) {
+                        groups.push(&entries[start..(i + 1)]);
+                        start = i + 1;
+                    }
+                }
+                if start < entries.len() {
+                    groups.push(&entries[start..]);
+                }
+                groups
+            },
+            _ => entries.chunks(fanout).collect(),
+        }
+    }
+
+    /// Store a single `Kind::Index` object
+    fn put_index(&self, depth: u64, entries: &[IndexEntry]) -> io::Result<IndexEntry>
+    {
+        let mut o = self.put(Kind::Index)?;
+        let mut b = [0u8;8];
+        byteorder::LittleEndian::write_u64(&mut b, depth);
+        o.write_all(&b)?;
+
+        let mut len = 0;
+        for e in entries {
+            byteorder::LittleEndian::write_u64(&mut b, e.len);
+            o.write_all(&b)?;
+            o.write_all(e.oid.as_bytes())?;
+            len += e.len;
+        }
+
+        Ok(IndexEntry {
+            len: len,
+            oid: o.commit()?,
+        })
+    }
+
+    /// Append the data below an index object (positioned just after its depth) to `out`. `len` is
+    /// the length the entry referring to the index gives it, `None` for the top of a blob.
+    fn load_index<R: Read>(&self, depth: u64, mut o: R, len: Option<u64>, out: &mut Vec<u8>) -> io::Result<()>
+    {
+        let too_long = || io::Error::new(io::ErrorKind::InvalidData, "index covers more data than can be held");
+        let end = match len {
+            Some(l) => Some((out.len() as u64).checked_add(l).ok_or_else(too_long)?),
+            None => None,
+        };
+        loop {
+            let e = match read_index_entry(&mut o, self.config.hash.oid_len())? {
+                Some(v) => v,
+                None => return Ok(()),
+            };
+
+            if blob::is_hole(&e.oid) {
+                if depth != 1 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("hole in index of depth {}", depth)));
+                }
+                let l = (out.len() as u64).checked_add(e.len).ok_or_else(too_long)?;
+                if let Some(end) = end {
+                    if l > end {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("hole of {} bytes runs past the end of its index", e.len)));
+                    }
+                }
+                if l > usize::max_value() as u64 {
+                    return Err(too_long());
+                }
+                out.resize(l as usize, 0);
+                continue;
+            }
+
+            let mut p = match self.get(&e.oid)? {
+                Some(v) => v,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("missing object {}", e.oid))),
+            };
+
+            let start = out.len();
+            match (depth, p.kind()) {
+                (1, Kind::Piece) => out.extend(p.as_ref()),
+                (d, Kind::Index) if d > 1 => {
+                    let sub_depth = read_u64(&mut p)?;
+                    if sub_depth != d - 1 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("index {} has depth {}, expected {}", e.oid, sub_depth, d - 1)));
+                    }
+                    self.load_index(sub_depth, p, Some(e.len), out)?;
+                },
+                (d, k) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                    format!("object {} is a {:?}, not allowed in index of depth {}",
+                                                            e.oid, k, d))),
+            }
+
+            if (out.len() - start) as u64 != e.len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("object {} has length {}, index says {}",
+                                                  e.oid, out.len() - start, e.len)));
+            }
+        }
     }
 
     ///
@@ -277,7 +699,7 @@ impl Store {
         let mut pieces = vec![];
         pieces.extend(kind.as_bytes().into_iter());
         let mut have_pieces = false;
-        let mut hr = hash_roll::bup::BupBuf::default();
+        let mut hr = self.config.new_chunker();
 
         let mut data = data;
 
@@ -332,16 +754,17 @@ impl Store {
                     },
                     Kind::Piece => {
                     }
-                    Kind::Tree => {
+                    Kind::Tree | Kind::Index | Kind::Snapshot => {
                         // fast-path this error
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Sub-kind Kind::Tree not allowed"));
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Sub-kind Kind::{:?} not allowed", sub_kind)));
                     }
                 }
 
                 // resolve other items
                 // TODO: use the length field
                 loop {
-                    let pi = match read_piece_entry(&mut o)? {
+                    let pi = match read_piece_entry(&mut o, self.config.hash.oid_len())? {
                         Some(v) => v,
                         None => break,
                     };
@@ -372,8 +795,17 @@ impl Store {
                 o.read_to_end(&mut data)?;
                 Ok(Some(data))
             },
-            Kind::Tree => {
-                Err(io::Error::new(io::ErrorKind::InvalidData, "Kind::Tree, not allowed"))
+            Kind::Index => {
+                let depth = read_u64(&mut o)?;
+                if depth == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "index has depth 0"));
+                }
+                let mut data = vec![];
+                self.load_index(depth, o, None, &mut data)?;
+                Ok(Some(data))
+            },
+            Kind::Tree | Kind::Snapshot => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("Kind::{:?}, not allowed", kind)))
             }
         }
     }
@@ -381,14 +813,175 @@ impl Store {
     // TODO: logically, this should probably be handled by Object or similar directly, and allow us
     // to seek & so forth.
     pub fn get_blob(&self, oid: &Oid) -> io::Result<Option<Vec<u8>>>
+    {
+        let mut data = vec![];
+        // FIXME: map error to include oid
+        Ok(self.copy_blob(oid, &mut data)?.map(|_| data))
+    }
+
+    /// Write the content of the blob `oid` to `w` without holding all of it in memory, returning
+    /// its length (or `None` if `oid` does not exist).
+    ///
+    /// With more than one thread (see `set_threads`) upcoming pieces are fetched & verified in
+    /// parallel, up to `read_ahead` pieces ahead of the one being written.
+    pub fn copy_blob<W: Write>(&self, oid: &Oid, w: W) -> io::Result<Option<u64>>
+    {
+        blob::copy_blob_to(self, oid, &mut blob::Zeros(w))
+    }
+
+    /// Like `copy_blob`, but writes to a file at its current offset, recreating holes by seeking
+    /// over them rather than writing zeros.
+    pub fn copy_blob_sparse(&self, oid: &Oid, f: &mut ::std::fs::File) -> io::Result<Option<u64>>
+    {
+        blob::copy_blob_to(self, oid, &mut blob::Sparse(f))
+    }
+
+    pub fn put_tree(&self, tree: &Tree) -> io::Result<Oid>
+    {
+        self.put_object(Kind::Tree, tree.to_bytes())
+    }
+
+    /// Load the tree `oid`, which must be a `Kind::Tree` object
+    pub fn get_tree(&self, oid: &Oid) -> io::Result<Option<Tree>>
+    {
+        match self.get_kind(oid, Kind::Tree)? {
+            Some(o) => Ok(Some(Tree::from_bytes(o.as_ref(), self.config.hash.oid_len())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_snapshot(&self, snapshot: &Snapshot) -> io::Result<Oid>
+    {
+        self.put_object(Kind::Snapshot, snapshot.to_bytes())
+    }
+
+    /// Load the snapshot `oid`, which must be a `Kind::Snapshot` object
+    pub fn get_snapshot(&self, oid: &Oid) -> io::Result<Option<Snapshot>>
+    {
+        match self.get_kind(oid, Kind::Snapshot)? {
+            Some(o) => Ok(Some(Snapshot::from_bytes(o.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_kind<'a>(&'a self, oid: &Oid, kind: Kind) -> io::Result<Option<Object<'a>>>
+    {
+        match self.get(oid)? {
+            Some(o) => {
+                if o.kind() != kind {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("object {} is a {:?}, expected a {:?}", oid, o.kind(), kind)));
+                }
+                Ok(Some(o))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Store the directory `source` & everything in it as a new snapshot
+    pub fn backup<P: AsRef<::std::path::Path>>(&self, source: P, opts: &BackupOptions) -> io::Result<BackupSummary>
+    {
+        backup::backup(self, source.as_ref(), opts)
+    }
+
+    /// Find the entry at `path` (components separated by `/`) below `root`, which is either a
+    /// snapshot or a tree. An empty path gives an entry for the root tree itself.
+    pub fn lookup(&self, root: &Oid, path: &str) -> io::Result<TreeEntry>
+    {
+        restore::lookup(self, root, path)
+    }
+
+    /// Recreate `entry` (& everything below it, for directories) at `target`
+    pub fn restore<P: AsRef<::std::path::Path>>(&self, entry: &TreeEntry, target: P, opts: &RestoreOptions)
+        -> io::Result<RestoreSummary>
+    {
+        restore::restore(self, entry, target.as_ref(), opts)
+    }
+
+    /// The differences between two entries (usually from `lookup`), & everything below them
+    pub fn diff(&self, a: &TreeEntry, b: &TreeEntry) -> io::Result<Vec<Change>>
+    {
+        diff::diff(self, a, b)
+    }
+
+    /// Stop keeping the snapshots that `policy` does not keep. With `dry_run`, only report what
+    /// would be removed.
+    pub fn prune(&self, policy: &KeepPolicy, dry_run: bool) -> io::Result<PruneSummary>
+    {
+        prune::prune(self, policy, dry_run)
+    }
+
+    /// Remove objects that are not reachable from any kept snapshot
+    pub fn gc(&self, opts: &GcOptions) -> io::Result<GcSummary>
+    {
+        gc::gc(self, opts)
+    }
+
+    /// Check that `oid` and every object reachable from it exist and are not corrupt.
+    pub fn verify(&self, oid: &Oid) -> io::Result<()>
     {
         let o = match self.get(oid)? {
-                Some(v) => v, None => return Ok(None),
+            Some(v) => v,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("missing object {}", oid))),
         };
 
-        let kind = o.kind();
-        // FIXME: map error to include oid
-        self.load_blob(kind, o)
+        match o.kind() {
+            Kind::Piece => Ok(()),
+            Kind::Blob | Kind::Index => blob::copy_blob_to(self, oid, &mut blob::Discard).map(|_| ()),
+            Kind::Tree => {
+                let t = Tree::from_bytes(o.as_ref(), self.config.hash.oid_len())?;
+                for e in t.entries {
+                    let r = match (e.kind, e.oid.as_ref()) {
+                        (EntryKind::File, Some(oid)) => self.verify_kind(oid, &[Kind::Piece, Kind::Blob, Kind::Index]),
+                        (EntryKind::Dir, Some(oid)) => self.verify_kind(oid, &[Kind::Tree]),
+                        _ => Ok(()),
+                    };
+                    r.and_then(|_| self.verify_xattrs(&e))
+                        .map_err(|err| io::Error::new(err.kind(),
+                                                      format!("{}/{}", String::from_utf8_lossy(&e.name), err)))?;
+                }
+                Ok(())
+            },
+            Kind::Snapshot => {
+                let s = Snapshot::from_bytes(o.as_ref())?;
+                let r = match s.root {
+                    Some(ref e) => self.verify_xattrs(e),
+                    None => Ok(()),
+                };
+                r.and_then(|_| self.verify_kind(&s.tree, &[Kind::Tree]))
+                    .map_err(|e| io::Error::new(e.kind(), format!("snapshot {}: {}", oid, e)))
+            },
+        }
+    }
+
+    /// `verify`, also checking that `oid` is one of `kinds`
+    fn verify_kind(&self, oid: &Oid, kinds: &[Kind]) -> io::Result<()>
+    {
+        match self.get(oid)? {
+            Some(o) => {
+                if !kinds.contains(&o.kind()) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("object {} is a {:?}, expected one of {:?}", oid, o.kind(), kinds)));
+                }
+            },
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("missing object {}", oid))),
+        }
+        self.verify(oid)
+    }
+
+    /// `verify` the blobs holding the large extended attribute values of `e`
+    fn verify_xattrs(&self, e: &TreeEntry) -> io::Result<()>
+    {
+        for x in &e.xattrs {
+            if let XattrValue::Blob(ref oid) = x.value {
+                self.verify_kind(oid, &[Kind::Piece, Kind::Blob, Kind::Index])
+                    .map_err(|err| io::Error::new(err.kind(), format!("xattr {}: {}",
+                                                                      String::from_utf8_lossy(&x.name), err)))?;
+            }
+        }
+        Ok(())
     }
 
     pub fn objects<'a>(&'a self) -> ObjectIter<'a>
@@ -401,35 +994,83 @@ struct PieceEntry {
     oid: Oid,
 }
 
-fn read_piece_entry<R: Read>(mut r: R) -> io::Result<Option<PieceEntry>>
+/// Read until `buf` is full or EOF is reached, returning the number of bytes read.
+fn read_full<R: Read>(mut r: R, buf: &mut [u8]) -> io::Result<usize>
 {
-    let mut p = [0u8;64];
+    let mut l = 0;
+    while l < buf.len() {
+        match r.read(&mut buf[l..]) {
+            Ok(0) => break,
+            Ok(n) => l += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(l)
+}
+
+fn read_u64<R: Read>(mut r: R) -> io::Result<u64>
+{
+    let mut b = [0u8;8];
+    r.read_exact(&mut b)?;
+    Ok(byteorder::LittleEndian::read_u64(&b))
+}
+
+/// Read a single entry containing an oid of `oid_len` bytes. Returns `None` at the end of the
+/// list.
+fn read_piece_entry<R: Read>(mut r: R, oid_len: usize) -> io::Result<Option<PieceEntry>>
+{
+    let mut p = vec![0u8;oid_len];
 
-    // FIXME: this read() likely needs more checking to catch short reads.
-    let l = r.read(&mut p)?;
+    let l = read_full(&mut r, &mut p)?;
     if l == 0 {
         return Ok(None);
     }
 
-    let oid = Oid::from_bytes(&p[..64]);
+    if l != p.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("truncated piece entry, have {} of {} bytes", l, p.len())));
+    }
+
+    let oid = Oid::from_bytes(p);
 
     Ok(Some(PieceEntry {
         oid: oid
     }))
 }
 
+/// An entry in a `Kind::Index` object
+struct IndexEntry {
+    /// bytes of data below this entry
+    len: u64,
+    oid: Oid,
+}
+
+/// Read a single index entry. Returns `None` at the end of the index.
+fn read_index_entry<R: Read>(mut r: R, oid_len: usize) -> io::Result<Option<IndexEntry>>
+{
+    let mut p = vec![0u8;8 + oid_len];
+
+    let l = read_full(&mut r, &mut p)?;
+    if l == 0 {
+        return Ok(None);
+    }
+
+    if l != p.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("truncated index entry, have {} of {} bytes", l, p.len())));
+    }
+
+    Ok(Some(IndexEntry {
+        len: byteorder::LittleEndian::read_u64(&p[..8]),
+        oid: Oid::from_bytes(&p[8..]),
+    }))
+}
+
 pub struct ObjectBuilder<'a> {
     parent: &'a Store,
     kind: Kind,
 
-    // FIXME: tempdir should really be something that will remove itself.
-    // Or we could just use tempfiles in a fixed-name directory.
-    // FIXME: right now we allow tempdir to continue to exist. Need cleanup mechanism.
-    tempdir: Dir,
-
-    // FIXME: right now we leak the on-disk File when no commit occurs.
-    file: ::std::fs::File,
-
     // FIXME: send data directly to file, hash progressively. Or hash speculatively if WriteAt &
     // Seek are needed.
     data: Vec<u8>,
@@ -438,16 +1079,9 @@ pub struct ObjectBuilder<'a> {
 impl<'a> ObjectBuilder<'a> {
     pub fn new(parent: &'a Store, kind: Kind) -> io::Result<Self>
     {
-        // TODO: encapsulate logic around tempdir, tempfiles, and renaming to allow us to be cross
-        // platform.
-        let t = parent.base.tempdir("vblock-temp.")?;
-        let f = t.create_file("new-object", 0o666)?;
-
         let mut x = ObjectBuilder {
             parent: parent,
             kind: kind,
-            tempdir: t,
-            file: f,
             data: Vec::with_capacity(Kind::len()),
         };
         x.data.extend(x.kind.as_bytes().iter());
@@ -459,12 +1093,9 @@ impl<'a> ObjectBuilder<'a> {
        Ok(self)
     }
 
-    pub fn commit(mut self) -> io::Result<Oid> {
-        let oid = Oid::from_data(&self.data);
-        self.file.write_all(&self.data[..])?;
-        let d = self.parent.object_dir(&oid)?;
-        let name = self.parent.object_name(&oid);
-        ::openat::rename(&self.tempdir, "new-object", &d, &name)?;
+    pub fn commit(self) -> io::Result<Oid> {
+        let oid = self.parent.config.hash.oid(&self.data);
+        self.parent.write_object(&oid, &self.data)?;
         Ok(oid)
     }
 }
@@ -494,7 +1125,10 @@ pub struct Object<'a> {
 
 impl<'a> Object<'a> {
     fn from_oid(parent: &'a Store, oid: Oid) -> io::Result<Option<Self>> {
-        let d = parent.object_dir(&oid)?;
+        let d = match parent.existing_object_dir(&oid)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
         let mut f = match d.open_file(&parent.object_name(&oid)) {
             Err(e) => {
                 return match e.kind() {
@@ -509,7 +1143,7 @@ impl<'a> Object<'a> {
         f.read_to_end(&mut b)?;
         f.seek(io::SeekFrom::Start(Kind::len() as u64))?;
 
-        let calc_key = Oid::from_data(&b);
+        let calc_key = parent.config.hash.oid(&b);
         if calc_key != oid {
             return Err(io::Error::new(io::ErrorKind::InvalidData, format!("piece {:?} is corrupt, has calculated oid {:?}",
                                                                             oid, calc_key)));
@@ -1,16 +1,905 @@
-extern crate hash_roll;
 #[macro_use]
 extern crate clap;
 extern crate rand;
+extern crate vblock;
+extern crate byteorder;
+extern crate libc;
 
-use clap::{Arg, SubCommand};
-use rand::Rng;
-use hash_roll::Split2;
+use clap::{Arg, ArgGroup, ArgMatches, SubCommand, AppSettings};
+use std::io::BufRead;
+
+mod bench;
+
+fn store_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("store")
+        .long("store")
+        .value_name("DIR")
+        .help("Directory containing the vblock store")
+        .takes_value(true)
+        .required(true)
+}
+
+fn threads_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("threads")
+        .long("threads")
+        .short("j")
+        .value_name("N")
+        .help("Number of threads used to hash or fetch pieces")
+        .takes_value(true)
+}
+
+fn read_ahead_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("read-ahead")
+        .long("read-ahead")
+        .value_name("PIECES")
+        .help("Number of pieces fetched ahead of the one being written, with more than one thread")
+        .takes_value(true)
+}
+
+fn chunker_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("chunker")
+        .long("chunker")
+        .value_name("ALGORITHM")
+        .help("Content defined chunking algorithm")
+        .takes_value(true)
+        .possible_values(&["bup", "rsyncable", "gear", "fastcdc", "buzhash", "zpaq"])
+}
+
+fn piece_size_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("piece-min")
+            .long("piece-min")
+            .value_name("BYTES")
+            .help("Minimum piece size")
+            .takes_value(true),
+        Arg::with_name("piece-avg")
+            .long("piece-avg")
+            .value_name("BYTES")
+            .help("Average piece size targeted by the chunker, a power of 2")
+            .takes_value(true),
+        Arg::with_name("piece-max")
+            .long("piece-max")
+            .value_name("BYTES")
+            .help("Maximum piece size, 0 for no limit")
+            .takes_value(true),
+    ]
+}
+
+fn xattr_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("no-xattrs")
+            .long("no-xattrs")
+            .help("Ignore extended attributes (including ACLs & file capabilities)"),
+        Arg::with_name("xattr-include")
+            .long("xattr-include")
+            .value_name("NAMESPACE")
+            .help("Only handle extended attributes in NAMESPACE (like user or security), may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("no-xattrs"),
+        Arg::with_name("xattr-exclude")
+            .long("xattr-exclude")
+            .value_name("NAMESPACE")
+            .help("Ignore extended attributes in NAMESPACE, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("no-xattrs"),
+    ]
+}
+
+fn xattr_filter(m: &ArgMatches) -> Option<vblock::XattrFilter> {
+    if m.is_present("no-xattrs") {
+        return None;
+    }
+    let list = |n| m.values_of(n).map(|v| v.map(|x| x.to_owned()).collect()).unwrap_or_default();
+    Some(vblock::XattrFilter {
+        include: list("xattr-include"),
+        exclude: list("xattr-exclude"),
+    })
+}
+
+/// Exclude rules from `--exclude`, `--include` & `--exclude-file`, in the order given
+fn exclude_rules(m: &ArgMatches) -> ::std::io::Result<Vec<String>> {
+    let mut rules = vec![];
+    for &(name, prefix) in &[("exclude", ""), ("include", "!")] {
+        if let (Some(v), Some(i)) = (m.values_of(name), m.indices_of(name)) {
+            rules.extend(i.zip(v.map(|r| format!("{}{}", prefix, r))));
+        }
+    }
+    if let (Some(v), Some(i)) = (m.values_of("exclude-file"), m.indices_of("exclude-file")) {
+        for (i, p) in i.zip(v) {
+            let d = ::std::fs::read_to_string(p).map_err(|e|
+                ::std::io::Error::new(e.kind(), format!("{}: {}", p, e)))?;
+            rules.extend(d.lines().map(|l| (i, l.to_owned())));
+        }
+    }
+    // sort_by_key is stable, keeping the lines of each file in order
+    rules.sort_by_key(|&(i, _)| i);
+    Ok(rules.into_iter().map(|(_, r)| r).collect())
+}
+
+fn parse_num<T: ::std::str::FromStr>(m: &ArgMatches, name: &str) -> ::std::io::Result<Option<T>>
+    where T::Err: ::std::fmt::Display
+{
+    match m.value_of(name) {
+        None => Ok(None),
+        Some(v) => v.parse::<T>().map(Some).map_err(|e| {
+            ::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                  format!("--{} requires an unsigned number, got {:?}: {}", name, v, e))
+        }),
+    }
+}
+
+/// `d` in seconds, for reports
+fn secs(d: ::std::time::Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+/// Piece size from the default, overridden by `piece_size_args()`
+fn piece_size(m: &ArgMatches) -> ::std::io::Result<vblock::PieceSize> {
+    let mut s = vblock::PieceSize::default();
+    if let Some(v) = parse_num(m, "piece-min")? {
+        s.min = v;
+    }
+    if let Some(v) = parse_num(m, "piece-avg")? {
+        s.avg = v;
+    }
+    if let Some(v) = parse_num(m, "piece-max")? {
+        s.max = v;
+    }
+    Ok(s)
+}
+
+fn bench_input(m: &ArgMatches) -> ::std::io::Result<bench::Input> {
+    Ok(if let Some(bytes) = parse_num(m, "input-random")? {
+        bench::Input::Random { bytes: bytes }
+    } else if let Some(f) = m.value_of("input-file") {
+        bench::Input::File(f.into())
+    } else if let Some(d) = m.value_of("input-dir") {
+        bench::Input::Dir(d.into())
+    } else {
+        bench::Input::Stdin
+    })
+}
+
+fn parse_params(v: &str) -> ::std::io::Result<vblock::PieceSize> {
+    let p: Vec<&str> = v.split(':').collect();
+    let n = |s: &str| s.parse::<usize>().map_err(|e| {
+        ::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                              format!("--params {:?}: {:?} is not an unsigned number: {}", v, s, e))
+    });
+    if p.len() != 3 {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                         format!("--params {:?} must be of the form MIN:AVG:MAX", v)));
+    }
+    Ok(vblock::PieceSize { min: n(p[0])?, avg: n(p[1])?, max: n(p[2])? })
+}
+
+fn bench_split_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let kinds: Vec<vblock::ChunkerKind> = if m.is_present("all-chunkers") {
+        vblock::ChunkerKind::all().to_owned()
+    } else {
+        match m.values_of("chunker") {
+            Some(v) => v.map(|n| vblock::ChunkerKind::from_name(n).unwrap()).collect(),
+            None => vec![vblock::ChunkerKind::default()],
+        }
+    };
+
+    let sizes: Vec<vblock::PieceSize> = match m.values_of("params") {
+        Some(v) => v.map(parse_params).collect::<::std::io::Result<_>>()?,
+        None => vec![piece_size(m)?],
+    };
+
+    let mut combos = vec![];
+    for k in &kinds {
+        for s in &sizes {
+            match s.check(*k) {
+                Ok(()) => combos.push((*k, s.clone())),
+                Err(e) => eprintln!("skipping {} with min {}, avg {}, max {}: {}", k, s.min, s.avg, s.max, e),
+            }
+        }
+    }
+    if combos.is_empty() {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                         "no usable chunker & piece size combinations"));
+    }
+
+    let mutations = match parse_num(m, "mutate")? {
+        Some(count) => Some(bench::Mutations {
+            count: count,
+            edits: match m.values_of("mutate-ops") {
+                Some(v) => v.map(|n| bench::Edit::from_name(n).unwrap()).collect(),
+                None => vec![bench::Edit::Insert, bench::Edit::Delete, bench::Edit::Flip],
+            },
+            seed: parse_num(m, "mutate-seed")?.unwrap_or(1),
+        }),
+        None => None,
+    };
+
+    let input = bench_input(m)?;
+    let input = if combos.len() > 1 || mutations.is_some() {
+        input.make_repeatable()?
+    } else {
+        input
+    };
+    let next = match mutations {
+        Some(mu) => Some(bench::Input::Mutated(Box::new(input.clone()), mu)),
+        None => m.value_of("next-version").map(|p| {
+            if ::std::path::Path::new(p).is_dir() {
+                bench::Input::Dir(p.into())
+            } else {
+                bench::Input::File(p.into())
+            }
+        }),
+    };
+
+    let format = m.value_of("format")
+        .map(|f| bench::Format::from_name(f).unwrap())
+        .unwrap_or(if combos.len() > 1 { bench::Format::Table } else { bench::Format::Report });
+
+    let mut runs = vec![];
+    for (k, s) in combos {
+        runs.push(bench::Run::new(k, s, &input, next.as_ref())?);
+    }
+
+    bench::compare(&runs, format);
+    Ok(())
+}
+
+fn open_store(m: &ArgMatches) -> vblock::Store {
+    let p = m.value_of("store").unwrap();
+    let mut s = match vblock::Store::with_path(p) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Error: could not open store {:?}: {}", p, e);
+            ::std::process::exit(1);
+        }
+    };
+    match parse_num(m, "threads") {
+        Ok(Some(n)) => s.set_threads(n),
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+    match parse_num(m, "read-ahead") {
+        Ok(Some(n)) => s.set_read_ahead(n),
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+    s
+}
+
+/// Turns off echo on stdin while it lives, if stdin is a terminal
+struct NoEcho {
+    saved: Option<libc::termios>,
+}
+
+impl NoEcho {
+    fn new() -> Self {
+        unsafe {
+            let mut t: libc::termios = ::std::mem::zeroed();
+            if libc::isatty(libc::STDIN_FILENO) != 1 || libc::tcgetattr(libc::STDIN_FILENO, &mut t) != 0 {
+                return NoEcho { saved: None };
+            }
+            let mut quiet = t;
+            quiet.c_lflag &= !libc::ECHO;
+            quiet.c_lflag |= libc::ECHONL;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &quiet) != 0 {
+                return NoEcho { saved: None };
+            }
+            NoEcho { saved: Some(t) }
+        }
+    }
+}
+
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        if let Some(ref t) = self.saved {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, t); }
+        }
+    }
+}
+
+/// Read a single line from stdin without echoing it, without the trailing newline
+fn read_passphrase(prompt: &str) -> Vec<u8> {
+    eprint!("{}: ", prompt);
+    let _quiet = NoEcho::new();
+    let stdin = ::std::io::stdin();
+    let mut l = String::new();
+    if let Err(e) = stdin.lock().read_line(&mut l) {
+        eprintln!("Error: could not read passphrase: {}", e);
+        ::std::process::exit(1);
+    }
+    while l.ends_with('\n') || l.ends_with('\r') {
+        l.pop();
+    }
+    l.into_bytes()
+}
+
+fn parse_oid(s: &str) -> vblock::Oid {
+    match vblock::Oid::from_hex(s) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Error: {:?} is not a valid oid: {}", s, e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn read_signing_key(path: &str) -> ::std::io::Result<vblock::SigningKey> {
+    use std::io::Read;
+    let mut h = String::new();
+    ::std::fs::File::open(path)?.read_to_string(&mut h)?;
+    vblock::SigningKey::from_hex(&h)
+}
+
+fn sign_keygen_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let k = vblock::SigningKey::generate()?;
+    let mut f = ::std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(m.value_of("FILE").unwrap())?;
+    f.write_all(format!("{}\n", k.to_hex()).as_bytes())?;
+    println!("{}", k.public().to_hex());
+    Ok(())
+}
+
+fn sign_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let k = read_signing_key(m.value_of("key").unwrap())?;
+    let oid = parse_oid(m.value_of("OID").unwrap());
+    if s.get(&oid)?.is_none() {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::NotFound,
+                                         format!("object {} does not exist", oid)));
+    }
+    s.signatures()?.add(&oid, &k)
+}
+
+fn verify_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let oid = parse_oid(m.value_of("OID").unwrap());
+    if !m.is_present("unsigned") {
+        let signers = s.signatures()?.trusted_signers(&oid)?;
+        if signers.is_empty() {
+            return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
+                                             format!("object {} has no valid signature from a trusted key", oid)));
+        }
+        for n in signers {
+            println!("good signature from {}", n);
+        }
+    }
+    s.verify(&oid)?;
+    println!("{} ok", oid);
+    Ok(())
+}
+
+fn trust_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let sigs = s.signatures()?;
+    match m.subcommand() {
+        ("list", _) => {
+            for (n, k) in sigs.trusted()? {
+                println!("{} {}", n, k.to_hex());
+            }
+        },
+        ("add", Some(sub_m)) => {
+            let k = vblock::PublicKey::from_hex(sub_m.value_of("PUBKEY").unwrap())?;
+            sigs.trust(sub_m.value_of("NAME").unwrap(), &k)?;
+        },
+        ("remove", Some(sub_m)) => {
+            sigs.untrust(sub_m.value_of("NAME").unwrap())?;
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn roots_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let roots = s.roots()?;
+    match m.subcommand() {
+        ("list", _) => {
+            for oid in roots.list()? {
+                println!("{}", oid);
+            }
+        },
+        ("add", Some(sub_m)) => {
+            let oid = parse_oid(sub_m.value_of("OID").unwrap());
+            if !s.contains(&oid)? {
+                return Err(::std::io::Error::new(::std::io::ErrorKind::NotFound,
+                                                 format!("object {} does not exist", oid)));
+            }
+            roots.add(&oid)?;
+        },
+        ("remove", Some(sub_m)) => {
+            let oid = parse_oid(sub_m.value_of("OID").unwrap());
+            if !roots.remove(&oid)? {
+                eprintln!("{} was not a root", oid);
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn key_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let keys = s.keys()?;
+    for (n, e) in keys.unreadable()? {
+        eprintln!("warning: skipping unreadable key file {:?}: {}", n, e);
+    }
+    match m.subcommand() {
+        ("list", _) => {
+            for n in keys.list()? {
+                println!("{}", n);
+            }
+        },
+        ("add", Some(sub_m)) => {
+            let name = sub_m.value_of("NAME").unwrap();
+            let unlock = if keys.list()?.is_empty() {
+                vec![]
+            } else {
+                read_passphrase("Existing passphrase")
+            };
+            let new = read_passphrase("New passphrase");
+            keys.add(name, &unlock, &new)?;
+        },
+        ("remove", Some(sub_m)) => {
+            keys.remove(sub_m.value_of("NAME").unwrap())?;
+        },
+        ("change-passphrase", Some(sub_m)) => {
+            let name = sub_m.value_of("NAME").unwrap();
+            let old = read_passphrase("Old passphrase");
+            let new = read_passphrase("New passphrase");
+            keys.change_passphrase(name, &old, &new)?;
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn init_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let p = m.value_of("store").unwrap();
+    ::std::fs::create_dir_all(p)?;
+
+    let mut c = vblock::Config::default();
+    if let Some(h) = m.value_of("hash") {
+        c.hash = vblock::HashAlg::from_name(h).unwrap();
+    }
+    if let Some(k) = m.value_of("chunker") {
+        c.chunker = vblock::ChunkerKind::from_name(k).unwrap();
+    }
+    c.piece_size = piece_size(m)?;
+    if let Some(l) = m.value_of("blob-layout") {
+        c.blob_layout = vblock::BlobLayout::from_name(l).unwrap();
+    }
+    if let Some(f) = parse_num(m, "fanout")? {
+        c.fanout = f;
+    }
+
+    let s = vblock::Store::with_path_config(p, c)?;
+    print!("{}", String::from_utf8_lossy(&s.config().to_bytes()));
+    Ok(())
+}
+
+fn put_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let mut w = s.blob_writer();
+    match m.value_of("FILE") {
+        None | Some("-") => {
+            let stdin = ::std::io::stdin();
+            ::std::io::copy(&mut stdin.lock(), &mut w)?;
+        },
+        Some(p) => {
+            ::std::io::copy(&mut ::std::fs::File::open(p)?, &mut w)?;
+        },
+    }
+    let oid = w.commit()?;
+    if m.is_present("keep") {
+        s.roots()?.add(&oid)?;
+    }
+    println!("{}", oid);
+    Ok(())
+}
+
+fn get_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let oid = parse_oid(m.value_of("OID").unwrap());
+    let missing = || ::std::io::Error::new(::std::io::ErrorKind::NotFound,
+                                           format!("object {} does not exist", oid));
+    // so a bad oid doesn't leave an empty output file behind
+    if !s.contains(&oid)? {
+        return Err(missing());
+    }
+    match m.value_of("output") {
+        None | Some("-") => {
+            let stdout = ::std::io::stdout();
+            let mut l = stdout.lock();
+            s.copy_blob(&oid, &mut l)?.ok_or_else(missing)?;
+        },
+        Some(p) => {
+            // written next to the output & renamed over it once complete, so a failed get
+            // leaves neither a partial file nor a clobbered one
+            let p = ::std::path::Path::new(p);
+            let name = p.file_name().ok_or_else(|| ::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                                                         format!("{:?} is not a file name", p)))?;
+            let mut tmp_name = ::std::ffi::OsString::from(".");
+            tmp_name.push(name);
+            tmp_name.push(".vblock-get");
+            let tmp = p.with_file_name(tmp_name);
+            let r = ::std::fs::File::create(&tmp)
+                .and_then(|mut f| s.copy_blob_sparse(&oid, &mut f)?.ok_or_else(missing))
+                .and_then(|_| ::std::fs::rename(&tmp, p));
+            if r.is_err() {
+                let _ = ::std::fs::remove_file(&tmp);
+            }
+            r?;
+        },
+    }
+    Ok(())
+}
+
+/// Print the (`u64` length, oid) entries of an index or the oids of a legacy blob
+fn print_entries(d: &[u8], oid_len: usize, with_len: bool) -> ::std::io::Result<()> {
+    use byteorder::ByteOrder;
+    let entry_len = oid_len + if with_len { 8 } else { 0 };
+    if d.len() % entry_len != 0 {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
+                                         format!("{} trailing bytes after entries", d.len() % entry_len)));
+    }
+    for e in d.chunks(entry_len) {
+        if with_len {
+            println!("{} {}", vblock::Oid::from_bytes(&e[8..]), byteorder::LittleEndian::read_u64(&e[..8]));
+        } else {
+            println!("{}", vblock::Oid::from_bytes(e));
+        }
+    }
+    Ok(())
+}
+
+fn cat_object_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    use byteorder::ByteOrder;
+    use std::io::Write;
+    let s = open_store(m);
+    let oid = parse_oid(m.value_of("OID").unwrap());
+    let o = match s.get(&oid)? {
+        Some(v) => v,
+        None => return Err(::std::io::Error::new(::std::io::ErrorKind::NotFound,
+                                                 format!("object {} does not exist", oid))),
+    };
+    let d = o.as_ref();
+    let kind = format!("{:?}", o.kind()).to_lowercase();
+
+    if m.is_present("type") {
+        println!("{}", kind);
+        return Ok(());
+    }
+
+    if m.is_present("raw") {
+        let stdout = ::std::io::stdout();
+        return stdout.lock().write_all(d);
+    }
+
+    println!("kind {}", kind);
+    println!("size {}", d.len());
+    println!();
+    let oid_len = s.config().hash.oid_len();
+    match o.kind() {
+        vblock::Kind::Index if d.len() >= 8 => {
+            println!("depth {}", byteorder::LittleEndian::read_u64(&d[..8]));
+            print_entries(&d[8..], oid_len, true)
+        },
+        vblock::Kind::Blob if d.len() >= 8 => {
+            println!("sub-kind {}", byteorder::LittleEndian::read_u64(&d[..8]));
+            print_entries(&d[8..], oid_len, false)
+        },
+        vblock::Kind::Tree => {
+            for e in vblock::Tree::from_bytes(d, oid_len)?.entries {
+                let oid = e.oid.map(|o| o.to_string()).unwrap_or_else(|| "-".to_owned());
+                let mut n = String::from_utf8_lossy(&e.name).into_owned();
+                if let Some(t) = e.target {
+                    n = format!("{} -> {}", n, String::from_utf8_lossy(&t));
+                }
+                println!("{} {} {} {}", e.kind, oid, e.size, n);
+            }
+            Ok(())
+        },
+        _ => {
+            let stdout = ::std::io::stdout();
+            stdout.lock().write_all(d)
+        },
+    }
+}
+
+fn backup_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let opts = vblock::BackupOptions {
+        tags: m.values_of("tag").map(|v| v.map(|x| x.to_owned()).collect()).unwrap_or_default(),
+        host: m.value_of("host").map(|x| x.to_owned()),
+        xattrs: xattr_filter(m),
+        force_rehash: m.is_present("force-rehash"),
+        parent: m.value_of("parent").map(parse_oid),
+        exclude: exclude_rules(m)?,
+        one_file_system: m.is_present("one-file-system"),
+        exclude_caches: m.is_present("exclude-caches"),
+        max_file_size: parse_num(m, "max-file-size")?,
+        retries: parse_num(m, "retries")?.unwrap_or(vblock::BackupOptions::default().retries),
+        fail_on_change: m.is_present("fail-on-change"),
+    };
+    let key = match m.value_of("sign-key") {
+        Some(p) => Some(read_signing_key(p)?),
+        None => None,
+    };
+
+    let start = ::std::time::Instant::now();
+    let r = s.backup(m.value_of("SOURCE").unwrap(), &opts)?;
+    if let Some(k) = key {
+        s.signatures()?.add(&r.snapshot, &k)?;
+    }
+
+    println!("snapshot {}", r.snapshot);
+    println!("{} files ({} hard links, {} unchanged), {} directories, {} symlinks, {} devices & fifos, {} skipped, {} excluded",
+             r.files, r.hardlinks, r.cached, r.dirs, r.symlinks, r.special, r.skipped, r.excluded);
+    println!("{} bytes read, {} bytes newly stored, {:.2}s",
+             r.bytes_read, r.bytes_stored, secs(start.elapsed()));
+    for p in &r.inconsistent {
+        eprintln!("warning: {} changed while being read", p);
+    }
+    Ok(())
+}
+
+/// Find the entry named by `SNAPSHOT[:PATH]`
+fn lookup_spec(s: &vblock::Store, spec: &str) -> ::std::io::Result<vblock::TreeEntry> {
+    let (oid, path) = match spec.find(':') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => (spec, ""),
+    };
+    s.lookup(&parse_oid(oid), path)
+}
+
+fn restore_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let e = lookup_spec(&s, m.value_of("SNAPSHOT").unwrap())?;
+
+    let opts = vblock::RestoreOptions {
+        existing: if m.is_present("overwrite") {
+            vblock::Existing::Overwrite
+        } else if m.is_present("skip-existing") {
+            vblock::Existing::Skip
+        } else {
+            vblock::Existing::Fail
+        },
+        verify_only: m.is_present("verify-only"),
+        numeric_owner: m.is_present("numeric-owner"),
+        xattrs: xattr_filter(m),
+    };
+    let target = m.value_of("TARGET").unwrap_or(".");
+    let r = s.restore(&e, target, &opts)?;
+
+    println!("{} {} files ({} bytes), {} hard links, {} directories, {} symlinks, {} devices & fifos, {} skipped",
+             if opts.verify_only { "verified" } else { "restored" },
+             r.files, r.bytes, r.hardlinks, r.dirs, r.symlinks, r.special, r.skipped);
+    if r.not_permitted > 0 {
+        eprintln!("warning: {} owners or devices could not be restored (not running as root?)", r.not_permitted);
+    }
+    Ok(())
+}
+
+fn snapshots_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let mut snaps = vec![];
+    for oid in s.snapshots()?.list()? {
+        match s.get_snapshot(&oid)? {
+            Some(v) => snaps.push((oid, v)),
+            None => eprintln!("warning: snapshot {} does not exist", oid),
+        }
+    }
+    snaps.sort_by_key(|&(_, ref v)| v.time);
+
+    for (oid, v) in snaps {
+        println!("{} {} {} {} files {} bytes{}{}", oid, vblock::DateTime::from_secs(v.time as i64), v.host,
+                 v.files, v.size,
+                 if v.tags.is_empty() { String::new() } else { format!(" [{}]", v.tags.join(", ")) },
+                 if v.inconsistent.is_empty() { String::new() } else { format!(" ({} inconsistent)", v.inconsistent.len()) });
+    }
+    Ok(())
+}
+
+/// Permissions like `ls -l` shows them
+fn mode_string(e: &vblock::TreeEntry) -> String {
+    let mut r = String::with_capacity(10);
+    r.push(match e.kind {
+        vblock::EntryKind::File => '-',
+        vblock::EntryKind::Dir => 'd',
+        vblock::EntryKind::Symlink => 'l',
+        vblock::EntryKind::BlockDevice => 'b',
+        vblock::EntryKind::CharDevice => 'c',
+        vblock::EntryKind::Fifo => 'p',
+    });
+    let mode = match e.mode {
+        Some(v) => v,
+        None => {
+            r.push_str("?????????");
+            return r;
+        },
+    };
+    for &(shift, special, set, unset) in &[(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')] {
+        let bits = mode >> shift;
+        r.push(if bits & 4 != 0 { 'r' } else { '-' });
+        r.push(if bits & 2 != 0 { 'w' } else { '-' });
+        r.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => set,
+            (false, true) => unset,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    r
+}
+
+fn ls_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let e = lookup_spec(&s, m.value_of("SNAPSHOT").unwrap())?;
+    let entries = if e.kind == vblock::EntryKind::Dir {
+        let oid = e.oid.as_ref().unwrap();
+        s.get_tree(oid)?.ok_or_else(|| ::std::io::Error::new(::std::io::ErrorKind::NotFound,
+                                                             format!("missing tree {}", oid)))?.entries
+    } else {
+        vec![e]
+    };
+
+    for e in &entries {
+        let owner = |name: &Option<String>, id: Option<u32>| match (name, id) {
+            (&Some(ref n), _) => n.clone(),
+            (&None, Some(id)) => id.to_string(),
+            (&None, None) => "?".to_owned(),
+        };
+        let size = match e.rdev {
+            Some(d) => format!("{}, {}", dev_major(d), dev_minor(d)),
+            None => e.size.to_string(),
+        };
+        let mtime = match e.mtime {
+            Some(t) => vblock::DateTime::from_secs(t.secs).to_string(),
+            None => "-".to_owned(),
+        };
+        print!("{} {:8} {:8} {:>10} {} {}", mode_string(e), owner(&e.user, e.uid), owner(&e.group, e.gid),
+               size, mtime, String::from_utf8_lossy(&e.name));
+        match e.target {
+            Some(ref t) => println!(" -> {}", String::from_utf8_lossy(t)),
+            None => println!(""),
+        }
+    }
+    Ok(())
+}
+
+/// The major & minor numbers of a device, as glibc encodes them
+fn dev_major(d: u64) -> u64 {
+    ((d >> 32) & 0xffff_f000) | ((d >> 8) & 0xfff)
+}
+
+fn dev_minor(d: u64) -> u64 {
+    ((d >> 12) & 0xffff_ff00) | (d & 0xff)
+}
+
+fn diff_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let a = lookup_spec(&s, m.value_of("SNAP_A").unwrap())?;
+    let b = lookup_spec(&s, m.value_of("SNAP_B").unwrap())?;
+    for c in s.diff(&a, &b)? {
+        println!("{} {}{}", c.kind.symbol(), String::from_utf8_lossy(&c.path),
+                 if c.entry == vblock::EntryKind::Dir { "/" } else { "" });
+    }
+    Ok(())
+}
+
+fn prune_cmd(m: &ArgMatches) -> ::std::io::Result<()> {
+    let s = open_store(m);
+    let policy = vblock::KeepPolicy {
+        last: parse_num(m, "keep-last")?.unwrap_or(0),
+        hourly: parse_num(m, "keep-hourly")?.unwrap_or(0),
+        daily: parse_num(m, "keep-daily")?.unwrap_or(0),
+        weekly: parse_num(m, "keep-weekly")?.unwrap_or(0),
+        monthly: parse_num(m, "keep-monthly")?.unwrap_or(0),
+        tags: m.values_of("keep-tag").map(|v| v.map(|x| x.to_owned()).collect()).unwrap_or_default(),
+    };
+    let dry_run = m.is_present("dry-run");
+    let r = s.prune(&policy, dry_run)?;
+
+    for e in &r.kept {
+        println!("keep   {} {} {} {} ({})", e.oid, vblock::DateTime::from_secs(e.snapshot.time as i64),
+                 e.snapshot.host, e.snapshot.source, e.reasons.join(", "));
+    }
+    for e in &r.removed {
+        println!("{} {} {} {} {}", if dry_run { "would remove" } else { "remove" }, e.oid,
+                 vblock::DateTime::from_secs(e.snapshot.time as i64), e.snapshot.host, e.snapshot.source);
+    }
+
+    if m.is_present("gc") {
+        // a dry run removed no refs, so tell gc which snapshots would be left. A real one lists
+        // them again, so a backup that finished meanwhile keeps its objects.
+        let snapshots = if dry_run {
+            Some(r.kept.iter().map(|e| e.oid.clone()).collect())
+        } else {
+            None
+        };
+        let g = s.gc(&vblock::GcOptions { dry_run: dry_run, snapshots: snapshots, ..Default::default() })?;
+        println!("{} {} unreferenced objects ({} bytes), kept {} ({} too recent to remove)",
+                 if dry_run { "would remove" } else { "removed" },
+                 g.removed, g.bytes_removed, g.kept, g.recent);
+    }
+    Ok(())
+}
+
+fn exit_on_err(r: ::std::io::Result<()>) {
+    if let Err(e) = r {
+        eprintln!("Error: {}", e);
+        ::std::process::exit(1);
+    }
+}
 
 fn main() {
     let matches = app_from_crate!()
         .subcommand(SubCommand::with_name("bench-split")
                     .about("Benchmark block splitting mechanisms (speed & deduplication), reads data from stdin by default")
+                    .arg(chunker_arg()
+                         .multiple(true)
+                         .use_delimiter(true)
+                         .help("Content defined chunking algorithms to compare"))
+                    .arg(Arg::with_name("all-chunkers")
+                         .long("all-chunkers")
+                         .help("Compare all chunking algorithms")
+                         .conflicts_with("chunker"))
+                    .args(&piece_size_args())
+                    .arg(Arg::with_name("params")
+                         .long("params")
+                         .value_name("MIN:AVG:MAX")
+                         .help("Piece size parameters to compare, may be given multiple times")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .conflicts_with_all(&["piece-min", "piece-avg", "piece-max"]))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .value_name("FORMAT")
+                         .help("Output format")
+                         .takes_value(true)
+                         .possible_values(&["report", "table", "csv", "json"]))
+                    .arg(Arg::with_name("next-version")
+                         .long("next-version")
+                         .value_name("PATH")
+                         .help("A later version of the input (file or directory). Reports how much of it is shared with the input")
+                         .takes_value(true))
+                    .arg(Arg::with_name("mutate")
+                         .long("mutate")
+                         .value_name("EDITS")
+                         .help("Use a copy of the input with EDITS random edits made to each file as the second version, to measure how well piece boundaries survive edits")
+                         .takes_value(true)
+                         .conflicts_with("next-version"))
+                    .arg(Arg::with_name("mutate-ops")
+                         .long("mutate-ops")
+                         .value_name("EDIT")
+                         .help("Kinds of edits made by --mutate (default: all)")
+                         .takes_value(true)
+                         .multiple(true)
+                         .use_delimiter(true)
+                         .possible_values(&["insert", "delete", "flip"])
+                         .requires("mutate"))
+                    .arg(Arg::with_name("mutate-seed")
+                         .long("mutate-seed")
+                         .value_name("SEED")
+                         .help("Seed for choosing the edits made by --mutate")
+                         .takes_value(true)
+                         .requires("mutate"))
                     .arg(Arg::with_name("input-random")
                          .short("r")
                          .value_name("BYTES")
@@ -29,42 +918,306 @@ fn main() {
                          .value_name("DIR")
                          .takes_value(true)
                          .conflicts_with_all(&["input-random", "input-file"]))
+        )
+        .subcommand(SubCommand::with_name("init")
+                    .about("Create a store, choosing the parameters that can't be changed later. Other commands create a store with the defaults if there is none.")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("hash")
+                         .long("hash")
+                         .value_name("ALGORITHM")
+                         .help("Hash algorithm used to generate oids")
+                         .takes_value(true)
+                         .possible_values(&["sha2-512", "sha2-256", "blake3"]))
+                    .arg(chunker_arg())
+                    .args(&piece_size_args())
+                    .arg(Arg::with_name("blob-layout")
+                         .long("blob-layout")
+                         .value_name("LAYOUT")
+                         .help("How the list of pieces making up a blob is stored")
+                         .takes_value(true)
+                         .possible_values(&["chunked", "tree", "cdc-tree"]))
+                    .arg(Arg::with_name("fanout")
+                         .long("fanout")
+                         .value_name("N")
+                         .help("Maximum entries in an index object")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("put")
+                    .about("Store a file (or stdin) as a blob and print its oid")
+                    .arg(store_arg())
+                    .arg(threads_arg())
+                    .arg(Arg::with_name("keep")
+                         .long("keep")
+                         .help("Add the blob to the store's roots, so prune --gc does not remove it"))
+                    .arg(Arg::with_name("FILE")
+                         .help("File to store, or - for stdin (the default)")))
+        .subcommand(SubCommand::with_name("get")
+                    .about("Write the content of a blob to stdout")
+                    .arg(store_arg())
+                    .arg(threads_arg())
+                    .arg(read_ahead_arg())
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .help("Write to FILE instead of stdout")
+                         .takes_value(true))
+                    .arg(Arg::with_name("OID").required(true)))
+        .subcommand(SubCommand::with_name("cat-object")
+                    .about("Show the kind and content of a single object")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("type")
+                         .short("t")
+                         .help("Only print the kind of the object"))
+                    .arg(Arg::with_name("raw")
+                         .long("raw")
+                         .help("Write the object's data (without its kind) to stdout unformatted")
+                         .conflicts_with("type"))
+                    .arg(Arg::with_name("OID").required(true)))
+        .subcommand(SubCommand::with_name("backup")
+                    .about("Store a directory tree as a new snapshot")
+                    .arg(store_arg())
+                    .arg(threads_arg())
+                    .arg(Arg::with_name("tag")
+                         .long("tag")
+                         .value_name("TAG")
+                         .help("Tag to record in the snapshot, may be given multiple times")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("host")
+                         .long("host")
+                         .value_name("NAME")
+                         .help("Host name to record in the snapshot (default: this machine's)")
+                         .takes_value(true))
+                    .args(&xattr_args())
+                    .arg(Arg::with_name("exclude")
+                         .long("exclude")
+                         .value_name("PATTERN")
+                         .help("Leave out entries matching PATTERN (.gitignore syntax), may be given multiple times")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("include")
+                         .long("include")
+                         .value_name("PATTERN")
+                         .help("Back up entries matching PATTERN even if excluded by an earlier --exclude")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("exclude-file")
+                         .long("exclude-file")
+                         .value_name("FILE")
+                         .help("Read exclude patterns from FILE, one per line like .gitignore")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("one-file-system")
+                         .short("x")
+                         .long("one-file-system")
+                         .help("Don't descend into directories on other filesystems"))
+                    .arg(Arg::with_name("exclude-caches")
+                         .long("exclude-caches")
+                         .help("Leave out the content of directories marked with a CACHEDIR.TAG"))
+                    .arg(Arg::with_name("max-file-size")
+                         .long("max-file-size")
+                         .value_name("BYTES")
+                         .help("Leave out files longer than BYTES")
+                         .takes_value(true))
+                    .arg(Arg::with_name("retries")
+                         .long("retries")
+                         .value_name("N")
+                         .help("Times to read a file again if it changes while being read (default: 2)")
+                         .takes_value(true))
+                    .arg(Arg::with_name("fail-on-change")
+                         .long("fail-on-change")
+                         .help("Fail instead of recording files still changing after --retries as inconsistent"))
+                    .arg(Arg::with_name("parent")
+                         .long("parent")
+                         .value_name("SNAPSHOT")
+                         .help("Don't read files that are unchanged since SNAPSHOT, an earlier backup of SOURCE")
+                         .takes_value(true))
+                    .arg(Arg::with_name("force-rehash")
+                         .long("force-rehash")
+                         .help("Read every file, even those unchanged since the last backup of SOURCE or --parent"))
+                    .arg(Arg::with_name("sign-key")
+                         .long("sign-key")
+                         .value_name("FILE")
+                         .help("Sign the snapshot with a key generated by sign-keygen")
+                         .takes_value(true))
+                    .arg(Arg::with_name("SOURCE").required(true)))
+        .subcommand(SubCommand::with_name("restore")
+                    .about("Recreate a snapshot, or a path within it, on disk")
+                    .arg(store_arg())
+                    .arg(threads_arg())
+                    .arg(read_ahead_arg())
+                    .arg(Arg::with_name("overwrite")
+                         .long("overwrite")
+                         .help("Replace files that already exist in TARGET"))
+                    .arg(Arg::with_name("skip-existing")
+                         .long("skip-existing")
+                         .help("Leave files that already exist in TARGET alone")
+                         .conflicts_with("overwrite"))
+                    .arg(Arg::with_name("verify-only")
+                         .long("verify-only")
+                         .help("Check that everything can be restored without writing anything"))
+                    .arg(Arg::with_name("numeric-owner")
+                         .long("numeric-owner")
+                         .help("Restore owners by uid & gid rather than by user & group name"))
+                    .args(&xattr_args())
+                    .arg(Arg::with_name("SNAPSHOT")
+                         .help("Snapshot (or tree) oid, optionally followed by :PATH to restore only PATH")
+                         .required(true))
+                    .arg(Arg::with_name("TARGET")
+                         .help("Where to restore to")
+                         .required_unless("verify-only")))
+        .subcommand(SubCommand::with_name("snapshots")
+                    .about("List snapshots, oldest first, with when, where & what they were taken of")
+                    .arg(store_arg()))
+        .subcommand(SubCommand::with_name("ls")
+                    .about("List a directory in a snapshot, like ls -l")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("SNAPSHOT")
+                         .help("Snapshot (or tree) oid, optionally followed by :PATH")
+                         .required(true)))
+        .subcommand(SubCommand::with_name("diff")
+                    .about("Show what was added (+), removed (-), modified (M) or had only metadata changed (U) between two snapshots")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("SNAP_A")
+                         .help("Older snapshot (or tree) oid, optionally followed by :PATH")
+                         .required(true))
+                    .arg(Arg::with_name("SNAP_B")
+                         .help("Newer snapshot (or tree) oid, optionally followed by :PATH")
+                         .required(true)))
+        .subcommand(SubCommand::with_name("prune")
+                    .about("Stop keeping snapshots not selected by any --keep option, per host & source")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("keep-last")
+                         .long("keep-last")
+                         .value_name("N")
+                         .help("Keep the newest N snapshots")
+                         .takes_value(true))
+                    .arg(Arg::with_name("keep-hourly")
+                         .long("keep-hourly")
+                         .value_name("N")
+                         .help("Keep the newest snapshot of each of the last N hours with one")
+                         .takes_value(true))
+                    .arg(Arg::with_name("keep-daily")
+                         .long("keep-daily")
+                         .value_name("N")
+                         .help("Keep the newest snapshot of each of the last N days with one")
+                         .takes_value(true))
+                    .arg(Arg::with_name("keep-weekly")
+                         .long("keep-weekly")
+                         .value_name("N")
+                         .help("Keep the newest snapshot of each of the last N weeks with one")
+                         .takes_value(true))
+                    .arg(Arg::with_name("keep-monthly")
+                         .long("keep-monthly")
+                         .value_name("N")
+                         .help("Keep the newest snapshot of each of the last N months with one")
+                         .takes_value(true))
+                    .arg(Arg::with_name("keep-tag")
+                         .long("keep-tag")
+                         .value_name("TAG")
+                         .help("Keep snapshots with TAG, may be given multiple times")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("gc")
+                         .long("gc")
+                         .help("Then remove objects no longer used by any kept snapshot or root. This \
+                                includes blobs stored with put, unless put --keep or roots add was used"))
+                    .arg(Arg::with_name("dry-run")
+                         .short("n")
+                         .long("dry-run")
+                         .help("Only print what would be removed"))
+                    .group(ArgGroup::with_name("keep")
+                           .args(&["keep-last", "keep-hourly", "keep-daily", "keep-weekly", "keep-monthly", "keep-tag"])
+                           .multiple(true)
+                           .required(true)))
+        .subcommand(SubCommand::with_name("key")
+                    .about("Manage the passphrases that unlock the store's master key")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .arg(store_arg())
+                    .subcommand(SubCommand::with_name("list")
+                                .about("List key names"))
+                    .subcommand(SubCommand::with_name("add")
+                                .about("Add a new passphrase (creating the master key if the store has none)")
+                                .arg(Arg::with_name("NAME").required(true)))
+                    .subcommand(SubCommand::with_name("remove")
+                                .about("Revoke a passphrase by removing its key")
+                                .arg(Arg::with_name("NAME").required(true)))
+                    .subcommand(SubCommand::with_name("change-passphrase")
+                                .about("Change the passphrase of an existing key")
+                                .arg(Arg::with_name("NAME").required(true)))
+        )
+        .subcommand(SubCommand::with_name("sign-keygen")
+                    .about("Generate a signing key, writing the secret to FILE and printing the public key")
+                    .arg(Arg::with_name("FILE").required(true)))
+        .subcommand(SubCommand::with_name("sign")
+                    .about("Sign an object (typically a snapshot)")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("key")
+                         .long("key")
+                         .value_name("FILE")
+                         .help("Signing key generated by sign-keygen")
+                         .takes_value(true)
+                         .required(true))
+                    .arg(Arg::with_name("OID").required(true)))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Check the signature of an object and the integrity of everything it refers to")
+                    .arg(store_arg())
+                    .arg(Arg::with_name("unsigned")
+                         .long("unsigned")
+                         .help("Only check integrity, do not require a trusted signature"))
+                    .arg(Arg::with_name("OID").required(true)))
+        .subcommand(SubCommand::with_name("trust")
+                    .about("Manage the public keys trusted by verify")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .arg(store_arg())
+                    .subcommand(SubCommand::with_name("list")
+                                .about("List trusted keys"))
+                    .subcommand(SubCommand::with_name("add")
+                                .about("Trust a public key")
+                                .arg(Arg::with_name("NAME").required(true))
+                                .arg(Arg::with_name("PUBKEY").required(true)))
+                    .subcommand(SubCommand::with_name("remove")
+                                .about("Stop trusting a public key")
+                                .arg(Arg::with_name("NAME").required(true)))
+        )
+        .subcommand(SubCommand::with_name("roots")
+                    .about("Manage the objects kept by prune --gc even though no snapshot refers to them")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .arg(store_arg())
+                    .subcommand(SubCommand::with_name("list")
+                                .about("List roots"))
+                    .subcommand(SubCommand::with_name("add")
+                                .about("Keep an object & everything it refers to")
+                                .arg(Arg::with_name("OID").required(true)))
+                    .subcommand(SubCommand::with_name("remove")
+                                .about("Stop keeping an object")
+                                .arg(Arg::with_name("OID").required(true)))
         ).get_matches();
 
 
     match matches.subcommand() {
-        ("bench-split", Some(sub_m)) => {
-            if let Some(bytes) = sub_m.value_of("input-random") {
-                let mut bytes = match bytes.parse::<usize>() {
-                    Err(e) => {
-                        eprintln!("--input-random requires a unsigned number of bytes, got '{:?}': {}", bytes, e);
-                        std::process::exit(1);
-                    },
-                    Ok(x) => { x },
-                };
-                println!("Benchmarking {} bytes of random data", bytes);
-                // FIXME: choose better buf size? move into box? allow variation?
-                let mut rng = rand::thread_rng();
-                let mut buf = [0u8;4096];
-                let mut hr = hash_roll::bup::BupBuf::default();
-
-                while bytes > buf.len() {
-                    rng.fill_bytes(&mut buf[..]);
-                    hr.push(&buf[..]);
-                    bytes -= buf.len();
-                }
-
-                rng.fill_bytes(&mut buf[0..bytes]);
-                hr.push(&buf[0..bytes]);
-
-            } else if let Some(in_file) = sub_m.value_of("input-file") {
-                eprintln!("input-file: {}", in_file);
-            } else if let Some(in_dir) = sub_m.value_of("input-dir") {
-                eprintln!("input-dir: {}", in_dir);
-            } else {
-                eprintln!("bench-split: {:?}", sub_m);
-            }
-        },
+        ("bench-split", Some(sub_m)) => exit_on_err(bench_split_cmd(sub_m)),
+        ("init", Some(sub_m)) => exit_on_err(init_cmd(sub_m)),
+        ("put", Some(sub_m)) => exit_on_err(put_cmd(sub_m)),
+        ("get", Some(sub_m)) => exit_on_err(get_cmd(sub_m)),
+        ("cat-object", Some(sub_m)) => exit_on_err(cat_object_cmd(sub_m)),
+        ("backup", Some(sub_m)) => exit_on_err(backup_cmd(sub_m)),
+        ("restore", Some(sub_m)) => exit_on_err(restore_cmd(sub_m)),
+        ("snapshots", Some(sub_m)) => exit_on_err(snapshots_cmd(sub_m)),
+        ("ls", Some(sub_m)) => exit_on_err(ls_cmd(sub_m)),
+        ("diff", Some(sub_m)) => exit_on_err(diff_cmd(sub_m)),
+        ("prune", Some(sub_m)) => exit_on_err(prune_cmd(sub_m)),
+        ("key", Some(sub_m)) => exit_on_err(key_cmd(sub_m)),
+        ("sign-keygen", Some(sub_m)) => exit_on_err(sign_keygen_cmd(sub_m)),
+        ("sign", Some(sub_m)) => exit_on_err(sign_cmd(sub_m)),
+        ("verify", Some(sub_m)) => exit_on_err(verify_cmd(sub_m)),
+        ("trust", Some(sub_m)) => exit_on_err(trust_cmd(sub_m)),
+        ("roots", Some(sub_m)) => exit_on_err(roots_cmd(sub_m)),
         (n, _) => {
             eprintln!("Error: unknown SubCommand {:?}", n);
             ::std::process::exit(1);
@@ -0,0 +1,164 @@
+//! Choosing which snapshots to keep
+//!
+//! Snapshots are grouped by host & source, and a policy is applied to each group separately, so
+//! frequent backups of one directory can't push out those of another. Within a group, snapshots
+//! are considered newest first. `hourly: N` keeps the newest snapshot of each of the last N
+//! hours that have one, & likewise for days, weeks (starting on Monday) and months. Periods are
+//! in UTC. A snapshot is kept if any rule keeps it; the rest are removed, whatever their age.
+//!
+//! Removing a snapshot only removes its ref in `snapshots/`. The objects it used stay until a
+//! `gc`.
+use std::collections::BTreeMap;
+use std::io;
+
+use snapshot::{Snapshot,DateTime};
+use {Store,Oid};
+
+#[derive(Debug,Clone,Default)]
+pub struct KeepPolicy {
+    /// Keep the newest N snapshots
+    pub last: u32,
+    pub hourly: u32,
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+    /// Keep every snapshot with one of these tags
+    pub tags: Vec<String>,
+}
+
+impl KeepPolicy {
+    /// Whether the policy keeps nothing at all
+    pub fn is_empty(&self) -> bool {
+        self.last == 0 && self.hourly == 0 && self.daily == 0 && self.weekly == 0 && self.monthly == 0 &&
+            self.tags.is_empty()
+    }
+}
+
+/// A snapshot & why it was kept (empty if it is removed)
+#[derive(Debug,Clone)]
+pub struct PruneEntry {
+    pub oid: Oid,
+    pub snapshot: Snapshot,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct PruneSummary {
+    pub kept: Vec<PruneEntry>,
+    pub removed: Vec<PruneEntry>,
+}
+
+/// Periods a snapshot falls into, as numbers that differ between periods
+fn period(secs: i64, rule: &str) -> i64 {
+    match rule {
+        "hourly" => secs.div_euclid(3600),
+        "daily" => secs.div_euclid(86400),
+        // the epoch was a Thursday
+        "weekly" => (secs.div_euclid(86400) + 3).div_euclid(7),
+        _ => {
+            let t = DateTime::from_secs(secs);
+            t.year * 12 + t.month as i64
+        },
+    }
+}
+
+/// Decide which of `group` (sorted newest first) to keep
+fn apply(policy: &KeepPolicy, group: &mut [PruneEntry]) {
+    for (i, e) in group.iter_mut().enumerate() {
+        if (i as u32) < policy.last {
+            e.reasons.push("last".to_owned());
+        }
+        for t in &e.snapshot.tags {
+            if policy.tags.contains(t) {
+                e.reasons.push(format!("tag {}", t));
+            }
+        }
+    }
+
+    for &(rule, n) in &[("hourly", policy.hourly), ("daily", policy.daily),
+                        ("weekly", policy.weekly), ("monthly", policy.monthly)] {
+        let mut kept = 0;
+        let mut prev = None;
+        for e in group.iter_mut() {
+            if kept >= n {
+                break;
+            }
+            let p = period(e.snapshot.time as i64, rule);
+            if prev != Some(p) {
+                e.reasons.push(rule.to_owned());
+                kept += 1;
+                prev = Some(p);
+            }
+        }
+    }
+}
+
+pub(crate) fn prune(store: &Store, policy: &KeepPolicy, dry_run: bool) -> io::Result<PruneSummary> {
+    if policy.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "refusing to remove every snapshot"));
+    }
+
+    let snapshots = store.snapshots()?;
+    let mut groups = BTreeMap::new();
+    for oid in snapshots.list()? {
+        // a ref to a missing snapshot is left for verify to complain about
+        let s = match store.get_snapshot(&oid)? {
+            Some(v) => v,
+            None => continue,
+        };
+        groups.entry((s.host.clone(), s.source.clone())).or_insert_with(Vec::new)
+            .push(PruneEntry { oid: oid, snapshot: s, reasons: vec![] });
+    }
+
+    let mut r = PruneSummary::default();
+    for (_, mut group) in groups {
+        group.sort_by(|a, b| b.snapshot.time.cmp(&a.snapshot.time));
+        apply(policy, &mut group);
+        for e in group {
+            if e.reasons.is_empty() {
+                if !dry_run {
+                    snapshots.remove(&e.oid)?;
+                }
+                r.removed.push(e);
+            } else {
+                r.kept.push(e);
+            }
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeepPolicy,PruneEntry,apply};
+    use snapshot::Snapshot;
+    use Oid;
+
+    fn at(time: u64, tags: &[&str]) -> PruneEntry {
+        let mut s = Snapshot::new(Oid::from_bytes(vec![0u8;32]));
+        s.time = time;
+        s.tags = tags.iter().map(|t| t.to_string()).collect();
+        PruneEntry { oid: Oid::from_bytes(vec![time as u8;32]), snapshot: s, reasons: vec![] }
+    }
+
+    #[test]
+    fn keep_policy() {
+        let h = 3600;
+        let d = 24 * h;
+        // newest first: 2 in the last hour, then one per day, one of them tagged
+        let mut g = vec![
+            at(10 * d + 30 * 60, &[]),
+            at(10 * d + 10 * 60, &[]),
+            at(9 * d, &[]),
+            at(8 * d, &["keep"]),
+            at(7 * d, &[]),
+            at(6 * d, &[]),
+        ];
+        let p = KeepPolicy { hourly: 2, daily: 3, tags: vec!["keep".to_owned()], ..Default::default() };
+        apply(&p, &mut g);
+        let kept: Vec<bool> = g.iter().map(|e| !e.reasons.is_empty()).collect();
+        assert_eq!(kept, vec![true, false, true, true, false, false]);
+        assert_eq!(g[0].reasons, vec!["hourly".to_owned(), "daily".to_owned()]);
+        assert_eq!(g[3].reasons, vec!["tag keep".to_owned(), "daily".to_owned()]);
+    }
+}
@@ -0,0 +1,311 @@
+//! Recreating a stored tree on disk
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path,PathBuf};
+
+use libc;
+use backup::XattrFilter;
+use tree::{TreeEntry,EntryKind,XattrValue};
+use {Store,Oid,Kind,sys};
+
+/// What to do when something already exists where an entry is to be restored. Existing
+/// directories are always merged into.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Existing {
+    Fail,
+    Skip,
+    Overwrite,
+}
+
+impl Default for Existing {
+    fn default() -> Self {
+        Existing::Fail
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct RestoreOptions {
+    pub existing: Existing,
+    /// Read & check everything that would be restored without writing anything
+    pub verify_only: bool,
+    /// Restore owners by uid & gid, ignoring the recorded user & group names
+    pub numeric_owner: bool,
+    /// Restore the extended attributes selected by the filter. `None` restores none.
+    pub xattrs: Option<XattrFilter>,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct RestoreSummary {
+    pub files: u64,
+    /// Entries recreated as hard links to an entry restored earlier
+    pub hardlinks: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Devices & fifos
+    pub special: u64,
+    /// Entries not restored because something already existed
+    pub skipped: u64,
+    /// Devices that could not be created, owners that could not be set & extended attributes
+    /// that could not be set, typically because we're not running as root
+    pub not_permitted: u64,
+    /// Bytes of file content restored, including holes
+    pub bytes: u64,
+}
+
+fn at(p: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", p.display(), e))
+}
+
+fn not_found(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, what.to_owned())
+}
+
+/// Find the entry at `path` (`/` separated) below `root`, a snapshot or tree. An empty path
+/// refers to the root tree itself, with the metadata of the backed up directory if the snapshot
+/// recorded it.
+pub(crate) fn lookup(store: &Store, root: &Oid, path: &str) -> io::Result<TreeEntry> {
+    let o = store.get(root)?.ok_or_else(|| not_found(&format!("object {} does not exist", root)))?;
+    let mut e = match o.kind() {
+        Kind::Snapshot => {
+            let s = store.get_snapshot(root)?.unwrap();
+            match s.root {
+                Some(e) => e,
+                None => {
+                    let mut e = TreeEntry::new("", EntryKind::Dir);
+                    e.oid = Some(s.tree);
+                    e
+                },
+            }
+        },
+        Kind::Tree => {
+            let mut e = TreeEntry::new("", EntryKind::Dir);
+            e.oid = Some(root.clone());
+            e
+        },
+        k => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       format!("object {} is a {:?}, not a snapshot or tree", root, k))),
+    };
+
+    for c in path.split('/').filter(|c| !c.is_empty()) {
+        if e.kind != EntryKind::Dir {
+            return Err(not_found(&format!("{}: not a directory", path)));
+        }
+        let t = store.get_tree(e.oid.as_ref().unwrap())?
+            .ok_or_else(|| not_found(&format!("missing tree {}", e.oid.as_ref().unwrap())))?;
+        e = t.get(c.as_bytes()).cloned().ok_or_else(|| not_found(&format!("{}: no such entry", path)))?;
+    }
+    Ok(e)
+}
+
+struct Restore<'a> {
+    store: &'a Store,
+    opts: &'a RestoreOptions,
+    summary: RestoreSummary,
+    /// Where entries with more than one link were restored, by (device, inode) at backup
+    linked: HashMap<(u64, u64), PathBuf>,
+}
+
+impl<'a> Restore<'a> {
+    /// Deal with anything already at `p`. Returns false if the entry should be skipped.
+    fn clear(&mut self, p: &Path, kind: EntryKind) -> io::Result<bool> {
+        let m = match ::std::fs::symlink_metadata(p) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+
+        if kind == EntryKind::Dir && m.is_dir() {
+            return Ok(true);
+        }
+
+        match self.opts.existing {
+            Existing::Fail => Err(io::Error::new(io::ErrorKind::AlreadyExists, "already exists")),
+            Existing::Skip => {
+                self.summary.skipped += 1;
+                Ok(false)
+            },
+            Existing::Overwrite => {
+                if m.is_dir() {
+                    ::std::fs::remove_dir_all(p)?;
+                } else {
+                    ::std::fs::remove_file(p)?;
+                }
+                Ok(true)
+            },
+        }
+    }
+
+    /// Apply the owner, permissions & modification time recorded in `e` to `p`.
+    ///
+    /// Failing to change the owner is not an error, as only root can give away files.
+    fn meta(&mut self, e: &TreeEntry, p: &Path) -> io::Result<()> {
+        if let (Some(uid), Some(gid)) = (e.uid, e.gid) {
+            let (uid, gid) = if self.opts.numeric_owner {
+                (uid, gid)
+            } else {
+                (e.user.as_ref().and_then(|n| sys::user_id(n)).unwrap_or(uid),
+                 e.group.as_ref().and_then(|n| sys::group_id(n)).unwrap_or(gid))
+            };
+            match sys::lchown(p, uid, gid) {
+                Ok(()) => {},
+                Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => self.summary.not_permitted += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        // after chown, which clears file capabilities
+        if let Some(ref filter) = self.opts.xattrs {
+            let xattrs: Vec<_> = e.xattrs.iter().filter(|x| filter.matches(&x.name)).collect();
+            let f = if xattrs.is_empty() {
+                None
+            } else {
+                match sys::XattrFile::open(p, e.kind) {
+                    Ok(f) => Some(f),
+                    Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                        self.summary.not_permitted += xattrs.len() as u64;
+                        None
+                    },
+                    Err(err) => return Err(err),
+                }
+            };
+            if let Some(f) = f {
+                for x in xattrs {
+                    let v = match x.value {
+                        XattrValue::Inline(ref v) => v.clone(),
+                        XattrValue::Blob(ref oid) => self.store.get_blob(oid)?
+                            .ok_or_else(|| not_found(&format!("missing blob {}", oid)))?,
+                    };
+                    match f.set(&x.name, &v) {
+                        Ok(()) => {},
+                        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied || sys::xattr_unsupported(err) =>
+                            self.summary.not_permitted += 1,
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+
+        // after chown, which can clear the setuid & setgid bits
+        if let Some(mode) = e.mode {
+            if e.kind != EntryKind::Symlink {
+                ::std::fs::set_permissions(p, ::std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+
+        if let Some(t) = e.mtime {
+            sys::set_mtime(p, t)?;
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self, e: &TreeEntry, p: &Path) -> io::Result<()> {
+        let link_key = match e.inode {
+            Some(i) if i.nlink > 1 && e.kind != EntryKind::Dir && !self.opts.verify_only => Some(i.key()),
+            _ => None,
+        };
+
+        if !self.opts.verify_only && !self.clear(p, e.kind).map_err(|err| at(p, err))? {
+            // the other links are made to what is already there, as the first one would have been
+            if let Some(k) = link_key {
+                self.linked.entry(k).or_insert_with(|| p.to_owned());
+            }
+            return Ok(());
+        }
+
+        if let Some(k) = link_key {
+            if let Some(first) = self.linked.get(&k) {
+                ::std::fs::hard_link(first, p).map_err(|err| at(p, err))?;
+                self.summary.hardlinks += 1;
+                return Ok(());
+            }
+        }
+
+        match e.kind {
+            EntryKind::Dir => {
+                let oid = e.oid.as_ref().unwrap();
+                let t = self.store.get_tree(oid)?
+                    .ok_or_else(|| at(p, not_found(&format!("missing tree {}", oid))))?;
+                if !self.opts.verify_only && !p.is_dir() {
+                    ::std::fs::create_dir(p).map_err(|err| at(p, err))?;
+                }
+                for c in &t.entries {
+                    self.entry(c, &p.join(OsStr::from_bytes(&c.name)))?;
+                }
+                self.summary.dirs += 1;
+                if self.opts.verify_only {
+                    return Ok(());
+                }
+            },
+            EntryKind::File => {
+                let oid = e.oid.as_ref().unwrap();
+                let r = if self.opts.verify_only {
+                    self.store.copy_blob(oid, io::sink())
+                } else {
+                    // create_new never follows a symlink that appeared in the mean time
+                    let mut f = ::std::fs::OpenOptions::new().write(true).create_new(true).open(p)
+                        .map_err(|err| at(p, err))?;
+                    self.store.copy_blob_sparse(oid, &mut f)
+                };
+                let len = r.map_err(|err| at(p, err))?
+                    .ok_or_else(|| at(p, not_found(&format!("missing blob {}", oid))))?;
+                if len != e.size {
+                    return Err(at(p, io::Error::new(io::ErrorKind::InvalidData,
+                                                    format!("blob {} has length {}, tree says {}", oid, len, e.size))));
+                }
+                self.summary.files += 1;
+                self.summary.bytes += len;
+                if self.opts.verify_only {
+                    return Ok(());
+                }
+            },
+            EntryKind::Symlink => {
+                self.summary.symlinks += 1;
+                if self.opts.verify_only {
+                    return Ok(());
+                }
+                let t = OsStr::from_bytes(e.target.as_ref().unwrap());
+                ::std::os::unix::fs::symlink(t, p).map_err(|err| at(p, err))?;
+            },
+            EntryKind::BlockDevice | EntryKind::CharDevice | EntryKind::Fifo => {
+                if self.opts.verify_only {
+                    self.summary.special += 1;
+                    return Ok(());
+                }
+                let kind = match e.kind {
+                    EntryKind::BlockDevice => libc::S_IFBLK,
+                    EntryKind::CharDevice => libc::S_IFCHR,
+                    _ => libc::S_IFIFO,
+                };
+                match sys::mknod(p, kind, e.mode.unwrap_or(0o600), e.rdev.unwrap_or(0)) {
+                    Ok(()) => self.summary.special += 1,
+                    Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                        self.summary.not_permitted += 1;
+                        return Ok(());
+                    },
+                    Err(err) => return Err(at(p, err)),
+                }
+            },
+        }
+
+        if let Some(k) = link_key {
+            self.linked.insert(k, p.to_owned());
+        }
+        self.meta(e, p).map_err(|err| at(p, err))
+    }
+}
+
+pub(crate) fn restore(store: &Store, entry: &TreeEntry, target: &Path, opts: &RestoreOptions)
+    -> io::Result<RestoreSummary>
+{
+    let mut r = Restore {
+        store: store,
+        opts: opts,
+        summary: RestoreSummary::default(),
+        linked: HashMap::new(),
+    };
+    r.entry(entry, target)?;
+    Ok(r.summary)
+}
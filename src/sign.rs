@@ -0,0 +1,242 @@
+//! Object signatures
+//!
+//! Any object (typically the root of a snapshot) may carry one or more Ed25519 signatures made
+//! with `sodalite::sign_attached`. As every object is named by the hash of its contents, a valid
+//! signature over an `Oid` authenticates everything reachable from that object.
+//!
+//! Signatures are kept outside of the objects themselves, in `signatures/<hex oid>`, one
+//! `<hex public key> <hex signature>` pair per line, so that signing does not change the oid being
+//! signed.
+//!
+//! Public keys that signatures must be made by to be accepted by `verify` are listed in
+//! `trusted-keys/<name>`, each file containing a single hex public key.
+use std::io;
+use hex::{FromHex,ToHex};
+use openat::Dir;
+use rand::Rng;
+
+use fs;
+use fs::invalid_data;
+use fs::DirVblockExt;
+use Oid;
+
+/// Prefixed to the oid to form the signed message, so that signatures made by vblock can't be
+/// confused with signatures over other data.
+const CONTEXT: &'static [u8] = b"vblock object signature\0";
+
+fn message(oid: &Oid) -> Vec<u8> {
+    let mut m = CONTEXT.to_owned();
+    m.extend(oid.as_bytes());
+    m
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct PublicKey {
+    inner: [u8;::sodalite::SIGN_PUBLIC_KEY_LEN],
+}
+
+impl PublicKey {
+    pub fn from_hex(h: &str) -> io::Result<Self> {
+        let v = Vec::<u8>::from_hex(h.trim()).map_err(invalid_data)?;
+        if v.len() != ::sodalite::SIGN_PUBLIC_KEY_LEN {
+            return Err(invalid_data(format!("public key has length {}, expected {}",
+                                            v.len(), ::sodalite::SIGN_PUBLIC_KEY_LEN)));
+        }
+        let mut k = [0u8;::sodalite::SIGN_PUBLIC_KEY_LEN];
+        k.copy_from_slice(&v);
+        Ok(PublicKey { inner: k })
+    }
+
+    pub fn to_hex(&self) -> String {
+        (&self.inner[..]).to_hex()
+    }
+
+    /// Check that `sig` is a signature of `oid` by this key
+    pub fn verify(&self, oid: &Oid, sig: &[u8]) -> bool {
+        if sig.len() != ::sodalite::SIGN_LEN {
+            return false;
+        }
+        let m = message(oid);
+        let mut sm = sig.to_owned();
+        sm.extend(&m);
+        let mut out = vec![0u8;sm.len()];
+        match ::sodalite::sign_attached_open(&mut out, &sm, &self.inner) {
+            Ok(l) => out[..l] == m[..],
+            Err(_) => false,
+        }
+    }
+}
+
+/// The secret half of a signing key. Stored on disk as the hex encoded 32 byte seed.
+pub struct SigningKey {
+    seed: [u8;::sodalite::SIGN_SEED_LEN],
+    public: PublicKey,
+    secret: [u8;::sodalite::SIGN_SECRET_KEY_LEN],
+}
+
+impl SigningKey {
+    fn from_seed(seed: [u8;::sodalite::SIGN_SEED_LEN]) -> Self {
+        let mut pk = [0u8;::sodalite::SIGN_PUBLIC_KEY_LEN];
+        let mut sk = [0u8;::sodalite::SIGN_SECRET_KEY_LEN];
+        ::sodalite::sign_keypair_seed(&mut pk, &mut sk, &seed);
+        SigningKey {
+            seed: seed,
+            public: PublicKey { inner: pk },
+            secret: sk,
+        }
+    }
+
+    pub fn generate() -> io::Result<Self> {
+        let mut seed = [0u8;::sodalite::SIGN_SEED_LEN];
+        ::rand::OsRng::new()?.fill_bytes(&mut seed[..]);
+        Ok(Self::from_seed(seed))
+    }
+
+    pub fn from_hex(h: &str) -> io::Result<Self> {
+        let v = Vec::<u8>::from_hex(h.trim()).map_err(invalid_data)?;
+        if v.len() != ::sodalite::SIGN_SEED_LEN {
+            return Err(invalid_data(format!("signing key has length {}, expected {}",
+                                            v.len(), ::sodalite::SIGN_SEED_LEN)));
+        }
+        let mut seed = [0u8;::sodalite::SIGN_SEED_LEN];
+        seed.copy_from_slice(&v);
+        Ok(Self::from_seed(seed))
+    }
+
+    pub fn to_hex(&self) -> String {
+        (&self.seed[..]).to_hex()
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    pub fn sign(&self, oid: &Oid) -> Vec<u8> {
+        let m = message(oid);
+        let mut sm = vec![0u8;m.len() + ::sodalite::SIGN_LEN];
+        ::sodalite::sign_attached(&mut sm, &m, &self.secret);
+        sm.truncate(::sodalite::SIGN_LEN);
+        sm
+    }
+}
+
+impl Drop for SigningKey {
+    fn drop(&mut self) {
+        // XXX: the compiler is free to elide this.
+        for b in self.seed.iter_mut().chain(self.secret.iter_mut()) {
+            *b = 0;
+        }
+    }
+}
+
+/// A signature found attached to an object
+#[derive(Debug,Clone)]
+pub struct Signature {
+    pub key: PublicKey,
+    pub sig: Vec<u8>,
+}
+
+/// The `signatures/` and `trusted-keys/` areas of a `Store`
+pub struct Signatures {
+    sigs: Dir,
+    trusted: Dir,
+}
+
+impl Signatures {
+    pub(crate) fn with_parent(base: &Dir) -> io::Result<Self> {
+        Ok(Signatures {
+            sigs: base.create_dir_open("signatures")?,
+            trusted: base.create_dir_open("trusted-keys")?,
+        })
+    }
+
+    /// All signatures attached to `oid`, valid or not.
+    pub fn get(&self, oid: &Oid) -> io::Result<Vec<Signature>> {
+        let s = match fs::read_file(&self.sigs, &oid.as_bytes().to_hex())? {
+            Some(s) => String::from_utf8(s).map_err(invalid_data)?,
+            None => return Ok(vec![]),
+        };
+
+        let mut r = vec![];
+        for line in s.lines() {
+            let mut p = line.split_whitespace();
+            let (k, sig) = match (p.next(), p.next()) {
+                (Some(k), Some(sig)) => (k, sig),
+                (None, _) => continue,
+                _ => return Err(invalid_data(format!("signature line {:?} is malformed", line))),
+            };
+            r.push(Signature {
+                key: PublicKey::from_hex(k)?,
+                sig: Vec::<u8>::from_hex(sig).map_err(invalid_data)?,
+            });
+        }
+        Ok(r)
+    }
+
+    /// Sign `oid` with `key`, replacing any previous signature by the same key.
+    pub fn add(&self, oid: &Oid, key: &SigningKey) -> io::Result<()> {
+        let mut sigs = self.get(oid)?;
+        sigs.retain(|s| &s.key != key.public());
+        sigs.push(Signature { key: key.public().clone(), sig: key.sign(oid) });
+
+        let mut d = String::new();
+        for s in sigs {
+            d.push_str(&format!("{} {}\n", s.key.to_hex(), s.sig.to_hex()));
+        }
+        fs::replace_file(&self.sigs, &oid.as_bytes().to_hex(), d.as_bytes(), 0o666)
+    }
+
+    /// Trusted public keys, by name
+    pub fn trusted(&self) -> io::Result<Vec<(String, PublicKey)>> {
+        let mut r = vec![];
+        for e in self.trusted.list_dir(".")? {
+            let e = e?;
+            let n = match e.file_name().to_str() {
+                Some(n) if !n.starts_with('.') => n.to_owned(),
+                _ => continue,
+            };
+            if let Some(k) = fs::read_file(&self.trusted, &n)? {
+                let k = String::from_utf8(k).map_err(invalid_data)?;
+                r.push((n, PublicKey::from_hex(&k)?));
+            }
+        }
+        r.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(r)
+    }
+
+    /// Names must be a single, non-hidden file in `trusted-keys/`
+    fn check_name(name: &str) -> io::Result<()> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid key name {:?}", name)))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn trust(&self, name: &str, key: &PublicKey) -> io::Result<()> {
+        Self::check_name(name)?;
+        fs::replace_file(&self.trusted, name, format!("{}\n", key.to_hex()).as_bytes(), 0o666)
+    }
+
+    pub fn untrust(&self, name: &str) -> io::Result<()> {
+        Self::check_name(name)?;
+        self.trusted.remove_file(name)
+    }
+
+    /// The names of the trusted keys that have made a valid signature of `oid`
+    pub fn trusted_signers(&self, oid: &Oid) -> io::Result<Vec<String>> {
+        let trusted = self.trusted()?;
+        let mut r = vec![];
+        for s in self.get(oid)? {
+            if !s.key.verify(oid, &s.sig) {
+                continue;
+            }
+            for &(ref n, ref k) in trusted.iter() {
+                if k == &s.key {
+                    r.push(n.clone());
+                }
+            }
+        }
+        Ok(r)
+    }
+}
@@ -0,0 +1,218 @@
+//! Snapshots
+//!
+//! A `Kind::Snapshot` object records the root tree of a backup along with when, where & from what
+//! it was taken. Its data is text, one `name value` pair per line, like the store `config`. The
+//! metadata of the directory that was backed up is kept as a hex encoded tree entry (see `tree`).
+//!
+//! Snapshots are kept by refs in `snapshots/`: one file per snapshot, named by (and containing)
+//! its hex oid. Signing a snapshot (see `Signatures`) authenticates the entire backup.
+use std::io;
+use hex::{FromHex,ToHex};
+
+use fs::invalid_data;
+use fs::RefDir;
+use tree::TreeEntry;
+use Oid;
+
+/// Values are stored one per line
+fn one_line(v: &str) -> String {
+    v.replace(|c| c == '\n' || c == '\r', "?")
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Snapshot {
+    /// The root `Kind::Tree`
+    pub tree: Oid,
+    /// Seconds since the unix epoch when the snapshot was started
+    pub time: u64,
+    pub host: String,
+    /// The directory that was backed up
+    pub source: String,
+    /// Metadata of `source` itself, as an unnamed directory entry with `tree` as its oid. `None`
+    /// for snapshots made before it was recorded.
+    pub root: Option<TreeEntry>,
+    pub tags: Vec<String>,
+    /// Number of files in the snapshot
+    pub files: u64,
+    /// Total length of the files in the snapshot
+    pub size: u64,
+    /// Paths (relative to `source`) of files that changed while they were being read, so their
+    /// content may not match any state they were ever in
+    pub inconsistent: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn new(tree: Oid) -> Self {
+        Snapshot {
+            tree: tree,
+            time: 0,
+            host: String::new(),
+            source: String::new(),
+            root: None,
+            tags: vec![],
+            files: 0,
+            size: 0,
+            inconsistent: vec![],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut s = format!("tree {}\ntime {}\nhost {}\nsource {}\n",
+                            self.tree, self.time, one_line(&self.host), one_line(&self.source));
+        if let Some(ref r) = self.root {
+            s.push_str(&format!("root {}\n", r.to_bytes().to_hex()));
+        }
+        for t in &self.tags {
+            s.push_str(&format!("tag {}\n", one_line(t)));
+        }
+        s.push_str(&format!("files {}\nsize {}\n", self.files, self.size));
+        for p in &self.inconsistent {
+            s.push_str(&format!("inconsistent {}\n", one_line(p)));
+        }
+        s.into_bytes()
+    }
+
+    pub fn from_bytes(d: &[u8]) -> io::Result<Self> {
+        let d = ::std::str::from_utf8(d).map_err(invalid_data)?;
+        let mut tree = None;
+        let mut root = None;
+        let mut s = Snapshot::new(Oid::from_bytes(vec![]));
+
+        for line in d.lines() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut p = line.splitn(2, ' ');
+            let k = p.next().unwrap();
+            let v = p.next().ok_or_else(|| invalid_data(format!("snapshot line {:?} has no value", line)))?;
+            match k {
+                "tree" => tree = Some(Oid::from_hex(v).map_err(invalid_data)?),
+                "time" => s.time = v.parse().map_err(invalid_data)?,
+                "host" => s.host = v.to_owned(),
+                "source" => s.source = v.to_owned(),
+                "root" => root = Some(Vec::<u8>::from_hex(v).map_err(invalid_data)?),
+                "tag" => s.tags.push(v.to_owned()),
+                "files" => s.files = v.parse().map_err(invalid_data)?,
+                "size" => s.size = v.parse().map_err(invalid_data)?,
+                "inconsistent" => s.inconsistent.push(v.to_owned()),
+                _ => return Err(invalid_data(format!("unknown snapshot field {:?}", k))),
+            }
+        }
+
+        s.tree = tree.ok_or_else(|| invalid_data("snapshot has no tree"))?;
+        if let Some(r) = root {
+            let e = TreeEntry::from_bytes(&r, s.tree.as_bytes().len())?;
+            if e.oid.as_ref() != Some(&s.tree) {
+                return Err(invalid_data("snapshot root entry does not refer to its tree"));
+            }
+            s.root = Some(e);
+        }
+        Ok(s)
+    }
+}
+
+/// A point in time broken down into its UTC calendar date & time of day
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// `secs` since the unix epoch
+    pub fn from_secs(secs: i64) -> Self {
+        let days = secs.div_euclid(86400);
+        let rem = secs.rem_euclid(86400) as u32;
+
+        // days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: rem / 3600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+        }
+    }
+}
+
+impl ::std::fmt::Display for DateTime {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Name of the machine we're running on, for recording in snapshots
+pub fn hostname() -> String {
+    for p in &["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if let Ok(h) = ::std::fs::read_to_string(p) {
+            let h = h.trim();
+            if !h.is_empty() {
+                return h.to_owned();
+            }
+        }
+    }
+    "localhost".to_owned()
+}
+
+/// The `snapshots/` area of a `Store`
+pub type Snapshots = RefDir;
+
+#[cfg(test)]
+mod test {
+    use super::{Snapshot,DateTime};
+    use tree::{TreeEntry,EntryKind};
+    use Oid;
+
+    #[test]
+    fn date_time() {
+        assert_eq!(DateTime::from_secs(0).to_string(), "1970-01-01 00:00:00");
+        assert_eq!(DateTime::from_secs(951_782_400).to_string(), "2000-02-29 00:00:00");
+        assert_eq!(DateTime::from_secs(1_792_409_045).to_string(), "2026-10-19 11:24:05");
+        assert_eq!(DateTime::from_secs(-1).to_string(), "1969-12-31 23:59:59");
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut s = Snapshot::new(Oid::from_bytes(vec![7u8;32]));
+        s.time = 1234;
+        s.host = "a host".to_owned();
+        s.source = "/home/x\ny".to_owned();
+        s.tags = vec!["daily".to_owned(), "pre upgrade".to_owned()];
+        s.files = 3;
+        s.size = 99;
+        s.inconsistent = vec!["var/log/syslog".to_owned()];
+        let mut root = TreeEntry::new("", EntryKind::Dir);
+        root.oid = Some(s.tree.clone());
+        root.mode = Some(0o1777);
+        s.root = Some(root.clone());
+
+        let s2 = Snapshot::from_bytes(&s.to_bytes()).unwrap();
+        assert_eq!(s2.source, "/home/x?y");
+        s.source = s2.source.clone();
+        assert_eq!(s, s2);
+
+        // the root entry must describe the snapshot's tree
+        root.oid = Some(Oid::from_bytes(vec![8u8;32]));
+        s.root = Some(root);
+        assert!(Snapshot::from_bytes(&s.to_bytes()).is_err());
+    }
+}
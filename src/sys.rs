@@ -0,0 +1,248 @@
+//! Thin wrappers around the libc calls that std does not expose
+//!
+//! Extended attributes are read & set through a file descriptor (`XattrFile`), so all the calls
+//! made for one entry refer to the same file even if its path is replaced meanwhile. Regular files
+//! & directories are opened for reading. Anything else is opened with `O_PATH`, as opening a
+//! device could have side effects & a symlink can't be opened at all. The `f*xattr` calls reject
+//! `O_PATH` descriptors, so those are reached through `/proc/self/fd`, which refers to the file
+//! the descriptor was opened on rather than following it any further.
+use std::ffi::{CString,CStr};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use libc;
+use tree::{Time,EntryKind};
+
+fn cstr(p: &[u8]) -> io::Result<CString> {
+    CString::new(p).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "nul byte in file name"))
+}
+
+fn cpath(p: &Path) -> io::Result<CString> {
+    cstr(p.as_os_str().as_bytes())
+}
+
+fn check(r: libc::c_int) -> io::Result<()> {
+    if r == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Change the owner of `p` without following symlinks
+pub fn lchown(p: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let p = cpath(p)?;
+    check(unsafe { libc::lchown(p.as_ptr(), uid, gid) })
+}
+
+/// Set the modification time of `p` without following symlinks, leaving the access time alone
+pub fn set_mtime(p: &Path, mtime: Time) -> io::Result<()> {
+    let p = cpath(p)?;
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: mtime.secs as libc::time_t, tv_nsec: mtime.nsecs as libc::c_long },
+    ];
+    check(unsafe { libc::utimensat(libc::AT_FDCWD, p.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })
+}
+
+/// Set the access & modification times of `f` to now
+pub fn touch(f: &File) -> io::Result<()> {
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+    ];
+    check(unsafe { libc::futimens(f.as_raw_fd(), times.as_ptr()) })
+}
+
+/// Take an advisory lock on `f`, held until it is closed. Fails with `WouldBlock` instead of
+/// waiting if `wait` is false & the lock is held elsewhere.
+pub fn flock(f: &File, exclusive: bool, wait: bool) -> io::Result<()> {
+    let mut op = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    if !wait {
+        op |= libc::LOCK_NB;
+    }
+    check(unsafe { libc::flock(f.as_raw_fd(), op) })
+}
+
+/// Create a device node or fifo. `kind` is one of `libc::S_IFBLK`, `S_IFCHR` or `S_IFIFO`.
+pub fn mknod(p: &Path, kind: libc::mode_t, mode: u32, rdev: u64) -> io::Result<()> {
+    let p = cpath(p)?;
+    check(unsafe { libc::mknod(p.as_ptr(), kind | (mode as libc::mode_t & 0o7777), rdev as libc::dev_t) })
+}
+
+/// Offset of the first data at or after `off` in `f`, `None` if the rest of the file is a hole.
+///
+/// Fails with `EINVAL` on filesystems that can't report holes.
+pub fn seek_data(f: &File, off: u64) -> io::Result<Option<u64>> {
+    let r = unsafe { libc::lseek(f.as_raw_fd(), off as libc::off_t, libc::SEEK_DATA) };
+    if r == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    Ok(Some(r as u64))
+}
+
+/// Offset of the first hole at or after `off` in `f`. The end of the file counts as a hole.
+pub fn seek_hole(f: &File, off: u64) -> io::Result<u64> {
+    let r = unsafe { libc::lseek(f.as_raw_fd(), off as libc::off_t, libc::SEEK_HOLE) };
+    if r == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(r as u64)
+}
+
+/// Size of the buffer handed to the `get*_r` functions
+const NSS_BUF: usize = 16 * 1024;
+
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; NSS_BUF];
+    let mut pw: libc::passwd = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let r = unsafe { libc::getpwuid_r(uid, &mut pw, buf.as_mut_ptr(), buf.len(), &mut res) };
+    if r != 0 || res.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(pw.pw_name) }.to_string_lossy().into_owned())
+}
+
+pub fn group_name(gid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; NSS_BUF];
+    let mut gr: libc::group = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let r = unsafe { libc::getgrgid_r(gid, &mut gr, buf.as_mut_ptr(), buf.len(), &mut res) };
+    if r != 0 || res.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(gr.gr_name) }.to_string_lossy().into_owned())
+}
+
+pub fn user_id(name: &str) -> Option<u32> {
+    let name = cstr(name.as_bytes()).ok()?;
+    let mut buf = vec![0 as libc::c_char; NSS_BUF];
+    let mut pw: libc::passwd = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let r = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pw, buf.as_mut_ptr(), buf.len(), &mut res) };
+    if r != 0 || res.is_null() {
+        return None;
+    }
+    Some(pw.pw_uid)
+}
+
+pub fn group_id(name: &str) -> Option<u32> {
+    let name = cstr(name.as_bytes()).ok()?;
+    let mut buf = vec![0 as libc::c_char; NSS_BUF];
+    let mut gr: libc::group = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let r = unsafe { libc::getgrnam_r(name.as_ptr(), &mut gr, buf.as_mut_ptr(), buf.len(), &mut res) };
+    if r != 0 || res.is_null() {
+        return None;
+    }
+    Some(gr.gr_gid)
+}
+
+/// Run a call that fills a buffer (like `fgetxattr`) with a buffer of the size it reports,
+/// retrying if it grows in between.
+fn sized_buf<F: FnMut(*mut libc::c_void, usize) -> libc::ssize_t>(mut f: F) -> io::Result<Vec<u8>> {
+    loop {
+        let n = f(ptr::null_mut(), 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; n as usize];
+        if n == 0 {
+            return Ok(buf);
+        }
+        let n = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+        buf.truncate(n as usize);
+        return Ok(buf);
+    }
+}
+
+/// Whether `e` means the filesystem (or the kind of file) doesn't support xattrs
+pub fn xattr_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// A file opened (without following symlinks) to read or set its extended attributes
+pub struct XattrFile {
+    f: File,
+    /// Opened with `O_PATH`, so the calls must go through `/proc/self/fd`
+    proc_path: Option<CString>,
+}
+
+impl XattrFile {
+    /// Open `p`, which is expected to be a `kind`
+    pub fn open(p: &Path, kind: EntryKind) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path_only = kind != EntryKind::File && kind != EntryKind::Dir;
+        let flags = libc::O_NOFOLLOW | libc::O_NOCTTY | libc::O_NONBLOCK |
+            if path_only { libc::O_PATH } else { 0 };
+        let f = ::std::fs::OpenOptions::new().read(true).custom_flags(flags).open(p)?;
+        let proc_path = if path_only {
+            Some(cstr(format!("/proc/self/fd/{}", f.as_raw_fd()).as_bytes())?)
+        } else {
+            None
+        };
+        Ok(XattrFile { f: f, proc_path: proc_path })
+    }
+
+    /// Names of the extended attributes
+    pub fn list(&self) -> io::Result<Vec<Vec<u8>>> {
+        let fd = self.f.as_raw_fd();
+        let buf = sized_buf(|b, l| unsafe {
+            match self.proc_path {
+                Some(ref p) => libc::listxattr(p.as_ptr(), b as *mut libc::c_char, l),
+                None => libc::flistxattr(fd, b as *mut libc::c_char, l),
+            }
+        });
+        let buf = match buf {
+            Ok(b) => b,
+            Err(ref e) if xattr_unsupported(e) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        Ok(buf.split(|&b| b == 0).filter(|n| !n.is_empty()).map(|n| n.to_owned()).collect())
+    }
+
+    /// Value of an extended attribute, `None` if it has been removed since it was listed
+    pub fn get(&self, name: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let fd = self.f.as_raw_fd();
+        let name = cstr(name)?;
+        let r = sized_buf(|b, l| unsafe {
+            match self.proc_path {
+                Some(ref p) => libc::getxattr(p.as_ptr(), name.as_ptr(), b, l),
+                None => libc::fgetxattr(fd, name.as_ptr(), b, l),
+            }
+        });
+        match r {
+            Ok(v) => Ok(Some(v)),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set(&self, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let name = cstr(name)?;
+        let v = value.as_ptr() as *const libc::c_void;
+        check(unsafe {
+            match self.proc_path {
+                Some(ref p) => libc::setxattr(p.as_ptr(), name.as_ptr(), v, value.len(), 0),
+                None => libc::fsetxattr(self.f.as_raw_fd(), name.as_ptr(), v, value.len(), 0),
+            }
+        })
+    }
+}
@@ -0,0 +1,523 @@
+//! Directory trees
+//!
+//! A `Kind::Tree` object describes a single directory as a sequence of entries sorted by name.
+//! Each entry is its length (`u64`) followed by that many bytes of fields. A field is a tag
+//! (`u64`), a length (`u64`) and then that many bytes of data. Integers are little endian.
+//!
+//! Every entry has a name & a type. Files refer to the blob holding their content, directories
+//! to the tree describing them, symlinks carry their target, and devices their device number.
+//!
+//! Entries may also carry POSIX metadata: permission bits, owner (both numeric & by name) and
+//! modification & change times with nanoseconds. Times are stored as seconds (`i64`) followed by
+//! nanoseconds (`u64`).
+//!
+//! Non-directory entries with more than one link record their device, inode & link count, so hard
+//! links (entries in one snapshot with the same device & inode) can be recreated.
+//!
+//! Extended attributes (which also hold POSIX ACLs & file capabilities) are stored one per field,
+//! as the length of the name (`u64`), the name, and then either the value itself or, for large
+//! values, the oid of a blob containing it.
+use std::io;
+use byteorder::{ByteOrder,LittleEndian};
+
+use fs::invalid_data;
+use Oid;
+
+const TAG_NAME: u64 = 1;
+const TAG_TYPE: u64 = 2;
+const TAG_OID: u64 = 3;
+const TAG_SIZE: u64 = 4;
+const TAG_TARGET: u64 = 5;
+const TAG_MODE: u64 = 6;
+const TAG_UID: u64 = 7;
+const TAG_GID: u64 = 8;
+const TAG_USER: u64 = 9;
+const TAG_GROUP: u64 = 10;
+const TAG_MTIME: u64 = 11;
+const TAG_CTIME: u64 = 12;
+const TAG_RDEV: u64 = 13;
+const TAG_XATTR: u64 = 14;
+const TAG_XATTR_BLOB: u64 = 15;
+const TAG_INODE: u64 = 16;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+}
+
+impl EntryKind {
+    fn raw(&self) -> u64 {
+        match *self {
+            EntryKind::File => 1,
+            EntryKind::Dir => 2,
+            EntryKind::Symlink => 3,
+            EntryKind::BlockDevice => 4,
+            EntryKind::CharDevice => 5,
+            EntryKind::Fifo => 6,
+        }
+    }
+
+    fn from_raw(v: u64) -> Option<Self> {
+        match v {
+            1 => Some(EntryKind::File),
+            2 => Some(EntryKind::Dir),
+            3 => Some(EntryKind::Symlink),
+            4 => Some(EntryKind::BlockDevice),
+            5 => Some(EntryKind::CharDevice),
+            6 => Some(EntryKind::Fifo),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+            EntryKind::Symlink => "symlink",
+            EntryKind::BlockDevice => "block-device",
+            EntryKind::CharDevice => "char-device",
+            EntryKind::Fifo => "fifo",
+        }
+    }
+}
+
+impl ::std::fmt::Display for EntryKind {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "{}", self.name())
+    }
+}
+
+/// A point in time, relative to the unix epoch
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct Time {
+    pub secs: i64,
+    pub nsecs: u32,
+}
+
+/// Identity of the file an entry was read from
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct Inode {
+    pub dev: u64,
+    pub ino: u64,
+    /// Number of hard links to the file
+    pub nlink: u64,
+}
+
+impl Inode {
+    /// Entries with the same key are hard links to the same file
+    pub fn key(&self) -> (u64, u64) {
+        (self.dev, self.ino)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum XattrValue {
+    Inline(Vec<u8>),
+    /// A blob holding the value
+    Blob(Oid),
+}
+
+/// An extended attribute
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Xattr {
+    /// Including the namespace, like `user.foo`
+    pub name: Vec<u8>,
+    pub value: XattrValue,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct TreeEntry {
+    /// A single path component
+    pub name: Vec<u8>,
+    pub kind: EntryKind,
+    /// The blob (for files) or tree (for directories) holding the content of the entry
+    pub oid: Option<Oid>,
+    /// Length of a file's content
+    pub size: u64,
+    /// Where a symlink points
+    pub target: Option<Vec<u8>>,
+    /// Device number of block & character devices
+    pub rdev: Option<u64>,
+
+    /// Permission bits (including setuid, setgid & sticky)
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Name of the owner, preferred over `uid` on restore when it exists
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub mtime: Option<Time>,
+    /// Recorded, but can't be restored
+    pub ctime: Option<Time>,
+    pub xattrs: Vec<Xattr>,
+    /// Only for files with more than one link
+    pub inode: Option<Inode>,
+}
+
+impl TreeEntry {
+    pub fn new<N: Into<Vec<u8>>>(name: N, kind: EntryKind) -> Self {
+        TreeEntry {
+            name: name.into(),
+            kind: kind,
+            oid: None,
+            size: 0,
+            target: None,
+            rdev: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            user: None,
+            group: None,
+            mtime: None,
+            ctime: None,
+            xattrs: vec![],
+            inode: None,
+        }
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        let e = self.fields();
+        put_u64(out, e.len() as u64);
+        out.extend(e);
+    }
+
+    fn fields(&self) -> Vec<u8> {
+        let mut e = vec![];
+        put_field(&mut e, TAG_NAME, &self.name);
+        put_u64_field(&mut e, TAG_TYPE, self.kind.raw());
+        if let Some(ref oid) = self.oid {
+            put_field(&mut e, TAG_OID, oid.as_bytes());
+        }
+        if self.size != 0 {
+            put_u64_field(&mut e, TAG_SIZE, self.size);
+        }
+        if let Some(ref t) = self.target {
+            put_field(&mut e, TAG_TARGET, t);
+        }
+        if let Some(v) = self.rdev {
+            put_u64_field(&mut e, TAG_RDEV, v);
+        }
+        if let Some(v) = self.mode {
+            put_u64_field(&mut e, TAG_MODE, v as u64);
+        }
+        if let Some(v) = self.uid {
+            put_u64_field(&mut e, TAG_UID, v as u64);
+        }
+        if let Some(v) = self.gid {
+            put_u64_field(&mut e, TAG_GID, v as u64);
+        }
+        if let Some(ref v) = self.user {
+            put_field(&mut e, TAG_USER, v.as_bytes());
+        }
+        if let Some(ref v) = self.group {
+            put_field(&mut e, TAG_GROUP, v.as_bytes());
+        }
+        if let Some(t) = self.mtime {
+            put_time_field(&mut e, TAG_MTIME, t);
+        }
+        if let Some(t) = self.ctime {
+            put_time_field(&mut e, TAG_CTIME, t);
+        }
+        if let Some(i) = self.inode {
+            let mut b = [0u8;24];
+            LittleEndian::write_u64(&mut b[..8], i.dev);
+            LittleEndian::write_u64(&mut b[8..16], i.ino);
+            LittleEndian::write_u64(&mut b[16..], i.nlink);
+            put_field(&mut e, TAG_INODE, &b);
+        }
+        for x in &self.xattrs {
+            let mut v = vec![];
+            put_u64(&mut v, x.name.len() as u64);
+            v.extend(&x.name);
+            let tag = match x.value {
+                XattrValue::Inline(ref d) => {
+                    v.extend(d);
+                    TAG_XATTR
+                },
+                XattrValue::Blob(ref oid) => {
+                    v.extend(oid.as_bytes());
+                    TAG_XATTR_BLOB
+                },
+            };
+            put_field(&mut e, tag, &v);
+        }
+        e
+    }
+
+    /// Encode the entry on its own, as a snapshot records the directory that was backed up. Such
+    /// an entry has an empty name.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.fields()
+    }
+
+    /// Decode an entry made by `to_bytes`
+    pub(crate) fn from_bytes(d: &[u8], oid_len: usize) -> io::Result<Self> {
+        let e = TreeEntry::from_fields(d, oid_len)?;
+        if !e.name.is_empty() || e.kind != EntryKind::Dir {
+            return Err(invalid_data(format!("root entry is a {} named {:?}, expected an unnamed directory",
+                                            e.kind, String::from_utf8_lossy(&e.name))));
+        }
+        Ok(e)
+    }
+
+    fn from_fields(mut d: &[u8], oid_len: usize) -> io::Result<Self> {
+        let mut name = None;
+        let mut kind = None;
+        let mut e = TreeEntry::new(vec![], EntryKind::File);
+
+        while !d.is_empty() {
+            let (tag, v) = take_field(&mut d)?;
+            match tag {
+                TAG_NAME => name = Some(v.to_owned()),
+                TAG_TYPE => {
+                    let k = field_u64(v)?;
+                    kind = Some(EntryKind::from_raw(k)
+                                .ok_or_else(|| invalid_data(format!("unknown tree entry type {}", k)))?);
+                },
+                TAG_OID => {
+                    if v.len() != oid_len {
+                        return Err(invalid_data(format!("tree entry oid has length {}, expected {}", v.len(), oid_len)));
+                    }
+                    e.oid = Some(Oid::from_bytes(v));
+                },
+                TAG_SIZE => e.size = field_u64(v)?,
+                TAG_TARGET => e.target = Some(v.to_owned()),
+                TAG_RDEV => e.rdev = Some(field_u64(v)?),
+                TAG_MODE => e.mode = Some(field_u32(v)?),
+                TAG_UID => e.uid = Some(field_u32(v)?),
+                TAG_GID => e.gid = Some(field_u32(v)?),
+                TAG_USER => e.user = Some(field_string(v)?),
+                TAG_GROUP => e.group = Some(field_string(v)?),
+                TAG_MTIME => e.mtime = Some(field_time(v)?),
+                TAG_CTIME => e.ctime = Some(field_time(v)?),
+                TAG_INODE => {
+                    if v.len() != 24 {
+                        return Err(invalid_data(format!("inode tree field has length {}", v.len())));
+                    }
+                    e.inode = Some(Inode {
+                        dev: LittleEndian::read_u64(&v[..8]),
+                        ino: LittleEndian::read_u64(&v[8..16]),
+                        nlink: LittleEndian::read_u64(&v[16..]),
+                    });
+                },
+                TAG_XATTR | TAG_XATTR_BLOB => {
+                    let mut v = v;
+                    let len = take_u64(&mut v)?;
+                    let name = take(&mut v, len)?.to_owned();
+                    let value = if tag == TAG_XATTR {
+                        XattrValue::Inline(v.to_owned())
+                    } else if v.len() == oid_len {
+                        XattrValue::Blob(Oid::from_bytes(v))
+                    } else {
+                        return Err(invalid_data(format!("xattr oid has length {}, expected {}", v.len(), oid_len)));
+                    };
+                    e.xattrs.push(Xattr { name: name, value: value });
+                },
+                _ => return Err(invalid_data(format!("unknown tree entry field {}", tag))),
+            }
+        }
+
+        e.name = name.ok_or_else(|| invalid_data("tree entry has no name"))?;
+        e.kind = kind.ok_or_else(|| invalid_data("tree entry has no type"))?;
+        let missing = match e.kind {
+            EntryKind::File | EntryKind::Dir if e.oid.is_none() => Some("oid"),
+            EntryKind::Symlink if e.target.is_none() => Some("target"),
+            EntryKind::BlockDevice | EntryKind::CharDevice if e.rdev.is_none() => Some("device number"),
+            _ => None,
+        };
+        if let Some(m) = missing {
+            return Err(invalid_data(format!("{} {:?} has no {}", e.kind, String::from_utf8_lossy(&e.name), m)));
+        }
+
+        Ok(e)
+    }
+}
+
+/// Entry names are single path components, so a tree can never refer outside of itself.
+fn check_name(name: &[u8]) -> io::Result<()> {
+    if name.is_empty() || name == b"." || name == b".." || name.iter().any(|&b| b == b'/' || b == 0) {
+        return Err(invalid_data(format!("invalid tree entry name {:?}", String::from_utf8_lossy(name))));
+    }
+    Ok(())
+}
+
+fn put_u64(out: &mut Vec<u8>, v: u64) {
+    let mut b = [0u8;8];
+    LittleEndian::write_u64(&mut b, v);
+    out.extend(&b[..]);
+}
+
+fn put_field(out: &mut Vec<u8>, tag: u64, v: &[u8]) {
+    put_u64(out, tag);
+    put_u64(out, v.len() as u64);
+    out.extend(v);
+}
+
+fn put_u64_field(out: &mut Vec<u8>, tag: u64, v: u64) {
+    let mut b = [0u8;8];
+    LittleEndian::write_u64(&mut b, v);
+    put_field(out, tag, &b);
+}
+
+/// Split `len` bytes off the front of `d`
+fn take<'a>(d: &mut &'a [u8], len: u64) -> io::Result<&'a [u8]> {
+    if (d.len() as u64) < len {
+        return Err(invalid_data(format!("truncated tree, need {} bytes, have {}", len, d.len())));
+    }
+    let (a, b) = d.split_at(len as usize);
+    *d = b;
+    Ok(a)
+}
+
+fn put_time_field(out: &mut Vec<u8>, tag: u64, t: Time) {
+    let mut b = [0u8;16];
+    LittleEndian::write_i64(&mut b[..8], t.secs);
+    LittleEndian::write_u64(&mut b[8..], t.nsecs as u64);
+    put_field(out, tag, &b);
+}
+
+fn take_u64(d: &mut &[u8]) -> io::Result<u64> {
+    Ok(LittleEndian::read_u64(take(d, 8)?))
+}
+
+fn take_field<'a>(d: &mut &'a [u8]) -> io::Result<(u64, &'a [u8])> {
+    let tag = take_u64(d)?;
+    let len = take_u64(d)?;
+    Ok((tag, take(d, len)?))
+}
+
+fn field_u64(v: &[u8]) -> io::Result<u64> {
+    if v.len() != 8 {
+        return Err(invalid_data(format!("integer tree field has length {}", v.len())));
+    }
+    Ok(LittleEndian::read_u64(v))
+}
+
+fn field_u32(v: &[u8]) -> io::Result<u32> {
+    let x = field_u64(v)?;
+    if x > ::std::u32::MAX as u64 {
+        return Err(invalid_data(format!("tree field value {} is too large", x)));
+    }
+    Ok(x as u32)
+}
+
+fn field_string(v: &[u8]) -> io::Result<String> {
+    String::from_utf8(v.to_owned()).map_err(invalid_data)
+}
+
+fn field_time(v: &[u8]) -> io::Result<Time> {
+    if v.len() != 16 {
+        return Err(invalid_data(format!("time tree field has length {}", v.len())));
+    }
+    let nsecs = LittleEndian::read_u64(&v[8..]);
+    if nsecs >= 1_000_000_000 {
+        return Err(invalid_data(format!("time has {} nanoseconds", nsecs)));
+    }
+    Ok(Time {
+        secs: LittleEndian::read_i64(&v[..8]),
+        nsecs: nsecs as u32,
+    })
+}
+
+/// The content of a `Kind::Tree` object
+#[derive(Debug,Clone,PartialEq,Eq,Default)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put the entries in the order they are stored in
+    pub fn sort(&mut self) {
+        self.entries.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Find an entry by name. The entries must be sorted.
+    pub fn get(&self, name: &[u8]) -> Option<&TreeEntry> {
+        self.entries.binary_search_by(|e| e.name.as_slice().cmp(name)).ok().map(|i| &self.entries[i])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for e in &self.entries {
+            e.write_to(&mut out);
+        }
+        out
+    }
+
+    /// Decode a tree whose oids are `oid_len` bytes long
+    pub fn from_bytes(mut d: &[u8], oid_len: usize) -> io::Result<Self> {
+        let mut entries: Vec<TreeEntry> = vec![];
+        while !d.is_empty() {
+            let len = take_u64(&mut d)?;
+            let e = TreeEntry::from_fields(take(&mut d, len)?, oid_len)?;
+            check_name(&e.name)?;
+            if let Some(prev) = entries.last() {
+                if prev.name >= e.name {
+                    return Err(invalid_data(format!("tree entry {:?} is out of order",
+                                                    String::from_utf8_lossy(&e.name))));
+                }
+            }
+            entries.push(e);
+        }
+
+        Ok(Tree { entries: entries })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Tree,TreeEntry,EntryKind,Time,Xattr,XattrValue,Inode};
+    use Oid;
+
+    #[test]
+    fn tree_round_trip() {
+        let mut t = Tree::new();
+        let mut f = TreeEntry::new("b", EntryKind::File);
+        f.oid = Some(Oid::from_bytes(vec![1u8;4]));
+        f.size = 12;
+        f.mode = Some(0o4755);
+        f.uid = Some(1000);
+        f.gid = Some(100);
+        f.user = Some("someone".to_owned());
+        f.mtime = Some(Time { secs: -5, nsecs: 999_999_999 });
+        f.ctime = Some(Time { secs: 1_500_000_000, nsecs: 1 });
+        f.inode = Some(Inode { dev: 0x803, ino: 1234567, nlink: 2 });
+        f.xattrs.push(Xattr { name: b"user.a".to_vec(), value: XattrValue::Inline(b"".to_vec()) });
+        f.xattrs.push(Xattr { name: b"user.b".to_vec(), value: XattrValue::Blob(Oid::from_bytes(vec![2u8;4])) });
+        let mut l = TreeEntry::new("a", EntryKind::Symlink);
+        l.target = Some(b"../x".to_vec());
+        let mut d = TreeEntry::new("c", EntryKind::CharDevice);
+        d.rdev = Some(0x0103);
+        t.entries.push(f);
+        t.entries.push(l);
+        t.entries.push(d);
+        t.sort();
+
+        let t2 = Tree::from_bytes(&t.to_bytes(), 4).unwrap();
+        assert_eq!(t, t2);
+        assert_eq!(t2.get(b"b").unwrap().size, 12);
+        assert!(t2.get(b"d").is_none());
+
+        assert!(Tree::from_bytes(&t.to_bytes(), 8).is_err());
+    }
+
+    #[test]
+    fn tree_bad_names() {
+        for n in &[&b""[..], b".", b"..", b"a/b"] {
+            let mut t = Tree::new();
+            let mut e = TreeEntry::new(*n, EntryKind::Symlink);
+            e.target = Some(b"x".to_vec());
+            t.entries.push(e);
+            assert!(Tree::from_bytes(&t.to_bytes(), 4).is_err());
+        }
+    }
+}
@@ -0,0 +1,520 @@
+extern crate libc;
+extern crate tempdir;
+extern crate vblock;
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt,PermissionsExt};
+
+fn write_file(p: &::std::path::Path, data: &[u8]) {
+    fs::File::create(p).unwrap().write_all(data).unwrap();
+}
+
+fn cpath(p: &::std::path::Path) -> ::std::ffi::CString {
+    use std::os::unix::ffi::OsStrExt;
+    ::std::ffi::CString::new(p.as_os_str().as_bytes()).unwrap()
+}
+
+/// Where a store at `store` keeps the object `oid`
+fn object_path(store: &::std::path::Path, oid: &vblock::Oid) -> ::std::path::PathBuf {
+    let hex = oid.to_string();
+    let mut p = store.to_path_buf();
+    for i in 0..4 {
+        p.push(&hex[i * 2..i * 2 + 2]);
+    }
+    p.join(&hex[8..])
+}
+
+/// Returns false if the filesystem doesn't support user xattrs
+fn set_xattr(p: &::std::path::Path, name: &str, v: &[u8]) -> bool {
+    let name = ::std::ffi::CString::new(name).unwrap();
+    let r = unsafe {
+        libc::setxattr(cpath(p).as_ptr(), name.as_ptr(), v.as_ptr() as *const libc::c_void, v.len(), 0)
+    };
+    if r == -1 {
+        let e = ::std::io::Error::last_os_error();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP), "setxattr failed: {}", e);
+        return false;
+    }
+    true
+}
+
+fn get_xattr(p: &::std::path::Path, name: &str) -> Vec<u8> {
+    let name = ::std::ffi::CString::new(name).unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+    let n = unsafe {
+        libc::getxattr(cpath(p).as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+    };
+    assert!(n >= 0, "getxattr failed: {}", ::std::io::Error::last_os_error());
+    buf.truncate(n as usize);
+    buf
+}
+
+#[test]
+fn backup_tree() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    let big: Vec<u8> = (0..300_000u32).map(|x| (x.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    write_file(&src.path().join("a"), b"hello");
+    fs::create_dir(src.path().join("sub")).unwrap();
+    write_file(&src.path().join("sub").join("big"), &big);
+    write_file(&src.path().join("empty"), b"");
+    ::std::os::unix::fs::symlink("sub/big", src.path().join("link")).unwrap();
+
+    let opts = vblock::BackupOptions {
+        tags: vec!["test".to_owned()],
+        host: Some("test-host".to_owned()),
+        ..Default::default()
+    };
+    let r = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!(r.files, 3);
+    assert_eq!(r.dirs, 2);
+    assert_eq!(r.symlinks, 1);
+    assert_eq!(r.bytes_read, 300_005);
+
+    assert_eq!(s.snapshots().unwrap().list().unwrap(), vec![r.snapshot.clone()]);
+    let snap = s.get_snapshot(&r.snapshot).unwrap().expect("snapshot does not exist");
+    assert_eq!(snap.tree, r.tree);
+    assert_eq!(snap.host, "test-host");
+    assert_eq!(snap.tags, vec!["test".to_owned()]);
+
+    let t = s.get_tree(&snap.tree).unwrap().expect("tree does not exist");
+    let names: Vec<&[u8]> = t.entries.iter().map(|e| &e.name[..]).collect();
+    assert_eq!(names, vec![&b"a"[..], b"empty", b"link", b"sub"]);
+    let a = t.get(b"a").unwrap();
+    assert_eq!(a.kind, vblock::EntryKind::File);
+    assert_eq!(s.get_blob(a.oid.as_ref().unwrap()).unwrap().unwrap(), b"hello");
+    assert_eq!(t.get(b"link").unwrap().target, Some(b"sub/big".to_vec()));
+
+    let sub = s.get_tree(t.get(b"sub").unwrap().oid.as_ref().unwrap()).unwrap().unwrap();
+    let b = sub.get(b"big").unwrap();
+    assert_eq!(b.size, big.len() as u64);
+    assert_eq!(s.get_blob(b.oid.as_ref().unwrap()).unwrap().unwrap(), big);
+
+    s.verify(&r.snapshot).expect("verify failed");
+
+    // nothing changed, so only the new snapshot object is stored
+    let r2 = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!(r2.tree, r.tree);
+    assert!(r2.bytes_stored < 1024, "stored {} bytes", r2.bytes_stored);
+}
+
+#[test]
+fn restore_tree() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let dst = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    write_file(&src.path().join("a"), b"hello");
+    fs::set_permissions(src.path().join("a"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::create_dir(src.path().join("sub")).unwrap();
+    write_file(&src.path().join("sub").join("b"), b"world");
+    fs::set_permissions(src.path().join("sub"), fs::Permissions::from_mode(0o750)).unwrap();
+    ::std::os::unix::fs::symlink("sub/b", src.path().join("link")).unwrap();
+    fs::set_permissions(src.path(), fs::Permissions::from_mode(0o751)).unwrap();
+    let r = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+
+    let root = s.lookup(&r.snapshot, "").unwrap();
+    assert_eq!(root.mode, Some(0o751));
+    let out = dst.path().join("out");
+    let rs = s.restore(&root, &out, &vblock::RestoreOptions::default()).expect("restore failed");
+    assert_eq!((rs.files, rs.dirs, rs.symlinks, rs.bytes), (2, 2, 1, 10));
+    assert_eq!(fs::read(out.join("a")).unwrap(), b"hello");
+    assert_eq!(fs::read(out.join("sub").join("b")).unwrap(), b"world");
+    assert_eq!(fs::read_link(out.join("link")).unwrap(), ::std::path::Path::new("sub/b"));
+    // "" is the backed up directory itself
+    for n in &["", "a", "sub", "sub/b"] {
+        let m1 = fs::metadata(src.path().join(n)).unwrap();
+        let m2 = fs::metadata(out.join(n)).unwrap();
+        assert_eq!(m1.mode(), m2.mode(), "{}", n);
+        assert_eq!((m1.mtime(), m1.mtime_nsec()), (m2.mtime(), m2.mtime_nsec()), "{}", n);
+    }
+
+    // existing files
+    write_file(&out.join("a"), b"changed");
+    assert!(s.restore(&root, &out, &vblock::RestoreOptions::default()).is_err());
+    let skip = vblock::RestoreOptions { existing: vblock::Existing::Skip, ..Default::default() };
+    assert_eq!(s.restore(&root, &out, &skip).unwrap().skipped, 3);
+    assert_eq!(fs::read(out.join("a")).unwrap(), b"changed");
+    let over = vblock::RestoreOptions { existing: vblock::Existing::Overwrite, ..Default::default() };
+    s.restore(&root, &out, &over).expect("restore failed");
+    assert_eq!(fs::read(out.join("a")).unwrap(), b"hello");
+
+    // a single file
+    let b = s.lookup(&r.snapshot, "sub/b").unwrap();
+    s.restore(&b, dst.path().join("b"), &vblock::RestoreOptions::default()).expect("restore failed");
+    assert_eq!(fs::read(dst.path().join("b")).unwrap(), b"world");
+    assert!(s.lookup(&r.snapshot, "sub/c").is_err());
+    assert!(s.lookup(&r.snapshot, "a/x").is_err());
+
+    let verify = vblock::RestoreOptions { verify_only: true, ..Default::default() };
+    let rs = s.restore(&root, dst.path().join("nothing"), &verify).expect("verify failed");
+    assert_eq!(rs.files, 2);
+    assert!(!dst.path().join("nothing").exists());
+}
+
+#[test]
+fn backup_hard_links() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let dst = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    write_file(&src.path().join("a"), b"linked content");
+    fs::create_dir(src.path().join("sub")).unwrap();
+    fs::hard_link(src.path().join("a"), src.path().join("sub").join("a2")).unwrap();
+    write_file(&src.path().join("b"), b"linked content");
+
+    let r = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+    assert_eq!(r.files, 3);
+    assert_eq!(r.hardlinks, 1);
+    assert_eq!(r.bytes_read, 28);
+
+    let root = s.lookup(&r.snapshot, "").unwrap();
+    let out = dst.path().join("out");
+    let rs = s.restore(&root, &out, &vblock::RestoreOptions::default()).expect("restore failed");
+    assert_eq!(rs.files, 2);
+    assert_eq!(rs.hardlinks, 1);
+
+    let a = fs::metadata(out.join("a")).unwrap();
+    let a2 = fs::metadata(out.join("sub").join("a2")).unwrap();
+    let b = fs::metadata(out.join("b")).unwrap();
+    assert_eq!(a.ino(), a2.ino());
+    assert_eq!(a.nlink(), 2);
+    assert!(a.ino() != b.ino());
+    assert_eq!(fs::read(out.join("sub").join("a2")).unwrap(), b"linked content");
+    // only files with more than one link record their inode
+    assert!(s.lookup(&r.snapshot, "a").unwrap().inode.is_some());
+    assert!(s.lookup(&r.snapshot, "b").unwrap().inode.is_none());
+
+    // a skipped first link is where the others link to
+    let out2 = dst.path().join("out2");
+    fs::create_dir(&out2).unwrap();
+    write_file(&out2.join("a"), b"already there");
+    let skip = vblock::RestoreOptions { existing: vblock::Existing::Skip, ..Default::default() };
+    let rs = s.restore(&root, &out2, &skip).expect("restore failed");
+    assert_eq!((rs.skipped, rs.hardlinks), (1, 1));
+    assert_eq!(fs::read(out2.join("sub").join("a2")).unwrap(), b"already there");
+    assert_eq!(fs::metadata(out2.join("a")).unwrap().ino(), fs::metadata(out2.join("sub").join("a2")).unwrap().ino());
+}
+
+#[test]
+fn backup_sparse() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let dst = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    // 8 MiB with a little data in the middle, a hole at each end
+    {
+        use std::io::{Seek,SeekFrom};
+        let mut f = fs::File::create(src.path().join("sparse")).unwrap();
+        f.set_len(8 << 20).unwrap();
+        f.seek(SeekFrom::Start(4 << 20)).unwrap();
+        f.write_all(b"in the middle").unwrap();
+    }
+    fs::File::create(src.path().join("hole")).unwrap().set_len(1 << 20).unwrap();
+
+    let r = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+    assert!(r.bytes_read < 1 << 20, "read {} bytes", r.bytes_read);
+    assert!(r.bytes_stored < 1 << 20, "stored {} bytes", r.bytes_stored);
+    s.verify(&r.snapshot).expect("verify failed");
+
+    let root = s.lookup(&r.snapshot, "").unwrap();
+    let out = dst.path().join("out");
+    let rs = s.restore(&root, &out, &vblock::RestoreOptions::default()).expect("restore failed");
+    assert_eq!(rs.bytes, (8 << 20) + (1 << 20));
+    assert_eq!(fs::read(out.join("sparse")).unwrap(), fs::read(src.path().join("sparse")).unwrap());
+    assert_eq!(fs::read(out.join("hole")).unwrap(), vec![0u8; 1 << 20]);
+    let m = fs::metadata(out.join("sparse")).unwrap();
+    assert_eq!(m.len(), 8 << 20);
+    assert!(m.blocks() * 512 < 1 << 20, "restored file uses {} blocks", m.blocks());
+}
+
+#[test]
+fn backup_cache() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    write_file(&src.path().join("a"), b"hello");
+    fs::create_dir(src.path().join("sub")).unwrap();
+    write_file(&src.path().join("sub").join("b"), b"world");
+    // files changed in the second a backup starts in aren't cached
+    ::std::thread::sleep(::std::time::Duration::from_millis(1100));
+
+    let opts = vblock::BackupOptions::default();
+    let r1 = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!((r1.cached, r1.bytes_read), (0, 10));
+
+    let r2 = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!((r2.files, r2.cached, r2.bytes_read), (2, 2, 0));
+    assert_eq!(r2.tree, r1.tree);
+
+    write_file(&src.path().join("a"), b"HELLO");
+    let r3 = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!((r3.cached, r3.bytes_read), (1, 5));
+    let t = s.get_tree(&r3.tree).unwrap().unwrap();
+    assert_eq!(s.get_blob(t.get(b"a").unwrap().oid.as_ref().unwrap()).unwrap().unwrap(), b"HELLO");
+
+    let force = vblock::BackupOptions { force_rehash: true, ..Default::default() };
+    let r4 = s.backup(src.path(), &force).expect("backup failed");
+    assert_eq!((r4.cached, r4.bytes_read), (0, 10));
+    assert_eq!(r4.tree, r3.tree);
+}
+
+#[test]
+fn backup_parent() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    write_file(&src.path().join("a"), b"hello");
+    fs::create_dir(src.path().join("sub")).unwrap();
+    write_file(&src.path().join("sub").join("b"), b"world");
+    let r1 = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+
+    // without the cache, only the parent can say what is unchanged
+    fs::remove_dir_all(tdb.path().join("cache")).unwrap();
+    write_file(&src.path().join("a"), b"hello again");
+    let opts = vblock::BackupOptions { parent: Some(r1.snapshot.clone()), ..Default::default() };
+    let r2 = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!((r2.files, r2.cached, r2.bytes_read), (2, 1, 11));
+
+    let t1 = s.get_tree(&r1.tree).unwrap().unwrap();
+    let t2 = s.get_tree(&r2.tree).unwrap().unwrap();
+    assert_eq!(t1.get(b"sub").unwrap().oid, t2.get(b"sub").unwrap().oid);
+    assert_eq!(s.get_blob(t2.get(b"a").unwrap().oid.as_ref().unwrap()).unwrap().unwrap(), b"hello again");
+
+    let force = vblock::BackupOptions { force_rehash: true, ..opts };
+    assert_eq!(s.backup(src.path(), &force).expect("backup failed").cached, 0);
+}
+
+#[test]
+fn backup_exclude() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    write_file(&src.path().join("a.log"), b"log");
+    write_file(&src.path().join("keep.log"), b"log");
+    write_file(&src.path().join("big"), &[0u8; 2000]);
+    fs::create_dir_all(src.path().join("x").join("node_modules")).unwrap();
+    write_file(&src.path().join("x").join("node_modules").join("m"), b"module");
+    fs::create_dir(src.path().join("cache")).unwrap();
+    write_file(&src.path().join("cache").join("CACHEDIR.TAG"),
+               b"Signature: 8a477f597d28d172789f06886806bc55\n# a cache\n");
+    write_file(&src.path().join("cache").join("c"), b"cached");
+
+    let opts = vblock::BackupOptions {
+        exclude: vec!["*.log".to_owned(), "!keep.log".to_owned(), "node_modules/".to_owned()],
+        exclude_caches: true,
+        max_file_size: Some(1000),
+        ..Default::default()
+    };
+    let r = s.backup(src.path(), &opts).expect("backup failed");
+    assert_eq!(r.excluded, 4);
+
+    let t = s.get_tree(&r.tree).unwrap().unwrap();
+    let names: Vec<&[u8]> = t.entries.iter().map(|e| &e.name[..]).collect();
+    assert_eq!(names, vec![&b"cache"[..], b"keep.log", b"x"]);
+    let x = s.get_tree(t.get(b"x").unwrap().oid.as_ref().unwrap()).unwrap().unwrap();
+    assert!(x.entries.is_empty());
+    let c = s.get_tree(t.get(b"cache").unwrap().oid.as_ref().unwrap()).unwrap().unwrap();
+    assert_eq!(c.entries.len(), 1);
+    assert_eq!(c.entries[0].name, b"CACHEDIR.TAG");
+}
+
+#[test]
+fn diff_snapshots() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    for n in &["a", "b", "c"] {
+        write_file(&src.path().join(n), n.as_bytes());
+    }
+    fs::create_dir(src.path().join("sub")).unwrap();
+    write_file(&src.path().join("sub").join("x"), b"x");
+    let r1 = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+
+    write_file(&src.path().join("a"), b"changed");
+    fs::set_permissions(src.path().join("b"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::remove_file(src.path().join("c")).unwrap();
+    fs::create_dir(src.path().join("d")).unwrap();
+    write_file(&src.path().join("d").join("y"), b"y");
+    write_file(&src.path().join("new"), b"new");
+    let r2 = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+
+    let a = s.lookup(&r1.snapshot, "").unwrap();
+    let b = s.lookup(&r2.snapshot, "").unwrap();
+    let changes: Vec<(String, char)> = s.diff(&a, &b).unwrap().into_iter()
+        .map(|c| (String::from_utf8(c.path).unwrap(), c.kind.symbol()))
+        .collect();
+    assert_eq!(changes, vec![
+        // entries were added & removed, so the source directory's mtime changed
+        ("".to_owned(), 'U'),
+        ("a".to_owned(), 'M'),
+        ("b".to_owned(), 'U'),
+        ("c".to_owned(), '-'),
+        ("d".to_owned(), '+'),
+        ("new".to_owned(), '+'),
+    ]);
+    assert!(s.diff(&a, &a).unwrap().is_empty());
+}
+
+#[test]
+fn prune_gc() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    write_file(&src.path().join("a"), b"only in the old snapshot");
+    write_file(&src.path().join("b"), b"in both");
+    let r1 = s.backup(src.path(), &vblock::BackupOptions::default()).expect("backup failed");
+    let old_a = s.lookup(&r1.snapshot, "a").unwrap().oid.unwrap();
+    fs::remove_file(src.path().join("a")).unwrap();
+    let opts = vblock::BackupOptions { tags: vec!["new".to_owned()], ..Default::default() };
+    let r2 = s.backup(src.path(), &opts).expect("backup failed");
+
+    assert!(s.prune(&vblock::KeepPolicy::default(), false).is_err());
+
+    let keep = vblock::KeepPolicy { tags: vec!["new".to_owned()], ..Default::default() };
+    let p = s.prune(&keep, true).unwrap();
+    assert_eq!(p.removed.len(), 1);
+    assert_eq!(s.snapshots().unwrap().list().unwrap().len(), 2);
+    // what a gc after the dry run would remove, as prune --dry-run --gc reports it
+    let kept = p.kept.iter().map(|e| e.oid.clone()).collect();
+    let preview = s.gc(&vblock::GcOptions { dry_run: true, grace_secs: 0, snapshots: Some(kept) }).unwrap();
+
+    let p = s.prune(&keep, false).unwrap();
+    assert_eq!(p.removed[0].oid, r1.snapshot);
+    assert_eq!(s.snapshots().unwrap().list().unwrap(), vec![r2.snapshot.clone()]);
+
+    let gc = vblock::GcOptions { grace_secs: 0, ..Default::default() };
+    let g = s.gc(&vblock::GcOptions { dry_run: true, ..gc.clone() }).unwrap();
+    assert!(g.removed > 0);
+    assert!(s.get_blob(&old_a).unwrap().is_some());
+
+    let g2 = s.gc(&gc).unwrap();
+    assert_eq!(g2.removed, g.removed);
+    assert_eq!(preview.removed, g2.removed);
+    assert_eq!(preview.bytes_removed, g2.bytes_removed);
+    assert!(s.get_blob(&old_a).unwrap().is_none());
+    s.verify(&r2.snapshot).expect("verify failed");
+    assert_eq!(s.gc(&gc).unwrap().removed, 0);
+}
+
+/// Set the modification time of `p` to `secs` since the epoch
+fn set_mtime(p: &::std::path::Path, secs: i64) {
+    let times = [
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: secs as libc::time_t, tv_nsec: 0 },
+    ];
+    let r = unsafe { libc::utimensat(libc::AT_FDCWD, cpath(p).as_ptr(), times.as_ptr(), 0) };
+    assert_eq!(r, 0, "utimensat failed: {}", ::std::io::Error::last_os_error());
+}
+
+/// Make every file below `p` look like it was last written at `secs`
+fn set_mtimes(p: &::std::path::Path, secs: i64) {
+    for e in fs::read_dir(p).unwrap() {
+        let e = e.unwrap();
+        if e.file_type().unwrap().is_dir() {
+            set_mtimes(&e.path(), secs);
+        } else {
+            set_mtime(&e.path(), secs);
+        }
+    }
+}
+
+#[test]
+fn gc_roots_and_recent() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let now = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let old = now - 2 * 60 * 60;
+
+    let mut x = 1u64;
+    let data: Vec<u8> = (0..(1 << 20)).map(|_| {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (x >> 56) as u8
+    }).collect();
+    let kept = s.put_blob(b"a root").unwrap();
+    s.roots().unwrap().add(&kept).unwrap();
+    let big = s.put_blob(&data).unwrap();
+    let lone = s.put_blob(b"not a root").unwrap();
+    set_mtimes(tdb.path(), old);
+
+    // storing an object that exists counts as writing it
+    assert_eq!(s.put_blob(b"not a root").unwrap(), lone);
+    // & a recent object keeps the old ones it refers to
+    set_mtime(&object_path(tdb.path(), &big), now);
+    let g = s.gc(&vblock::GcOptions::default()).unwrap();
+    assert_eq!(g.removed, 0);
+    assert_eq!(g.kept, 1);
+    assert!(g.recent > 2, "{:?}", g);
+    assert_eq!(s.get_blob(&big).unwrap().unwrap(), data);
+
+    // gc waits for no backup
+    let lock = fs::File::open(tdb.path().join("lock")).unwrap();
+    assert_eq!(unsafe { libc::flock(::std::os::unix::io::AsRawFd::as_raw_fd(&lock), libc::LOCK_SH) }, 0);
+    let e = s.gc(&vblock::GcOptions::default()).err().expect("gc ran while the store was locked");
+    assert_eq!(e.kind(), ::std::io::ErrorKind::WouldBlock);
+    drop(lock);
+
+    set_mtimes(tdb.path(), old);
+    let g = s.gc(&vblock::GcOptions::default()).unwrap();
+    assert_eq!(g.kept, 1);
+    assert_eq!(g.recent, 0);
+    assert!(s.get_blob(&big).unwrap().is_none());
+    assert!(s.get_blob(&lone).unwrap().is_none());
+    assert_eq!(s.get_blob(&kept).unwrap().unwrap(), b"a root");
+
+    assert!(s.roots().unwrap().remove(&kept).unwrap());
+    assert_eq!(s.gc(&vblock::GcOptions { grace_secs: 0, ..Default::default() }).unwrap().removed, 1);
+    assert!(s.roots().unwrap().list().unwrap().is_empty());
+}
+
+#[test]
+fn backup_xattrs() {
+    let src = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let dst = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+
+    let big = vec![b'x'; 4000];
+    write_file(&src.path().join("f"), b"data");
+    if !set_xattr(&src.path().join("f"), "user.small", b"v") {
+        return;
+    }
+    set_xattr(&src.path().join("f"), "user.big", &big);
+    set_xattr(src.path(), "user.root", b"r");
+    ::std::os::unix::fs::symlink("f", src.path().join("link")).unwrap();
+
+    let opts = vblock::BackupOptions { xattrs: Some(vblock::XattrFilter::default()), ..Default::default() };
+    let r = s.backup(src.path(), &opts).expect("backup failed");
+    let f = s.lookup(&r.snapshot, "f").unwrap();
+    let names: Vec<&[u8]> = f.xattrs.iter().map(|x| &x.name[..]).collect();
+    assert_eq!(names, vec![&b"user.big"[..], b"user.small"]);
+    let big_oid = match f.xattrs[0].value {
+        vblock::XattrValue::Blob(ref oid) => oid.clone(),
+        ref v => panic!("large value stored inline: {:?}", v),
+    };
+    assert!(s.lookup(&r.snapshot, "link").unwrap().xattrs.is_empty());
+    s.verify(&r.snapshot).expect("verify failed");
+
+    let root = s.lookup(&r.snapshot, "").unwrap();
+    let out = dst.path().join("out");
+    let ropts = vblock::RestoreOptions { xattrs: Some(vblock::XattrFilter::default()), ..Default::default() };
+    s.restore(&root, &out, &ropts).expect("restore failed");
+    assert_eq!(get_xattr(&out.join("f"), "user.small"), b"v");
+    assert_eq!(get_xattr(&out.join("f"), "user.big"), big);
+    assert_eq!(get_xattr(&out, "user.root"), b"r");
+
+    // verify looks at the blobs holding xattr values
+    fs::remove_file(object_path(tdb.path(), &big_oid)).unwrap();
+    assert!(s.verify(&r.snapshot).is_err());
+}
@@ -0,0 +1,47 @@
+extern crate tempdir;
+extern crate vblock;
+
+#[test]
+fn key_add_remove() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let k = s.keys().expect("failed to open keys");
+
+    assert!(k.list().unwrap().is_empty());
+    assert!(k.unlock(b"a").is_err());
+
+    k.add("alice", b"", b"a").expect("first add failed");
+    let mk = k.unlock(b"a").expect("unlock failed");
+
+    // adding requires an existing passphrase once a master key exists
+    assert!(k.add("bob", b"wrong", b"b").is_err());
+    k.add("bob", b"a", b"b").expect("second add failed");
+    assert_eq!(k.list().unwrap(), vec!["alice".to_owned(), "bob".to_owned()]);
+    assert_eq!(k.unlock(b"b").unwrap().as_bytes(), mk.as_bytes());
+
+    k.remove("alice").expect("remove failed");
+    assert!(k.unlock(b"a").is_err());
+    assert!(k.remove("bob").is_err());
+
+    k.change_passphrase("bob", b"b", b"c").expect("change failed");
+    assert!(k.unlock(b"b").is_err());
+    assert_eq!(k.unlock(b"c").unwrap().as_bytes(), mk.as_bytes());
+}
+
+#[test]
+fn key_unlock_skips_bad_files() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let k = s.keys().expect("failed to open keys");
+
+    k.add("bob", b"", b"b").expect("add failed");
+    // sorts before bob, so it is tried first
+    ::std::fs::write(tdb.path().join("keys").join("alice"), b"garbage").unwrap();
+
+    let bad = k.unreadable().unwrap();
+    assert_eq!(bad.len(), 1);
+    assert_eq!(bad[0].0, "alice");
+    k.unlock(b"b").expect("unlock failed");
+    let e = k.unlock(b"x").err().expect("unlock with a wrong passphrase succeeded");
+    assert!(e.to_string().contains("alice"), "{}", e);
+}
@@ -0,0 +1,44 @@
+extern crate tempdir;
+extern crate vblock;
+
+#[test]
+fn sign_verify() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let sigs = s.signatures().expect("failed to open signatures");
+
+    let oid = s.put_blob(b"signed data").expect("put failed");
+    let k = vblock::SigningKey::generate().unwrap();
+    let other = vblock::SigningKey::generate().unwrap();
+
+    assert!(sigs.trusted_signers(&oid).unwrap().is_empty());
+    sigs.add(&oid, &k).expect("sign failed");
+    sigs.add(&oid, &other).expect("sign failed");
+    // signed, but not by a trusted key
+    assert!(sigs.trusted_signers(&oid).unwrap().is_empty());
+
+    let pk = vblock::PublicKey::from_hex(&k.public().to_hex()).unwrap();
+    sigs.trust("build-host", &pk).expect("trust failed");
+    assert_eq!(sigs.trusted_signers(&oid).unwrap(), vec!["build-host".to_owned()]);
+
+    let oid2 = s.put_blob(b"unsigned data").expect("put failed");
+    assert!(sigs.trusted_signers(&oid2).unwrap().is_empty());
+
+    s.verify(&oid).expect("verify failed");
+}
+
+#[test]
+fn untrust_names() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let sigs = s.signatures().expect("failed to open signatures");
+
+    let k = vblock::SigningKey::generate().unwrap();
+    sigs.trust("a", k.public()).expect("trust failed");
+    for n in &["", ".", "..", "../config", "a/b"] {
+        assert_eq!(sigs.untrust(n).unwrap_err().kind(), ::std::io::ErrorKind::InvalidInput);
+    }
+    assert!(tdb.path().join("config").exists());
+    sigs.untrust("a").expect("untrust failed");
+    assert!(sigs.trusted().unwrap().is_empty());
+}
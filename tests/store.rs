@@ -10,6 +10,7 @@ use openat::Dir;
 use std::ffi::{CStr, CString};
 use ::std::os::unix::ffi::OsStrExt;
 use std::io::Read;
+use std::io::Write;
 
 /*
 macro_rules! check { ($e:expr) => (
@@ -103,6 +104,30 @@ fn piece_put_twice() {
     s.put_object(vblock::Kind::Piece, b"hi").unwrap();
 }
 
+#[test]
+fn missing_object() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let oid = s.put_object(vblock::Kind::Piece, b"hi").unwrap();
+    let list = || {
+        let mut v: Vec<_> = ::std::fs::read_dir(tdb.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        v.sort();
+        v
+    };
+    let before = list();
+
+    // shares the first level directory with `oid`, but not the rest
+    let mut b = oid.as_bytes().to_owned();
+    b[1] ^= 1;
+    for missing in &[vblock::Oid::from_bytes(vec![0xab; 64]), vblock::Oid::from_bytes(b)] {
+        assert!(!s.contains(missing).unwrap());
+        assert!(s.get(missing).unwrap().is_none());
+    }
+    assert!(s.contains(&oid).unwrap());
+    assert_eq!(list(), before);
+    assert_eq!(::std::fs::read_dir(tdb.path().join(&oid.to_string()[..2])).unwrap().count(), 1);
+}
+
 #[test]
 fn blob_put() {
     fn prop(data: Vec<u8>) -> bool {
@@ -183,6 +208,37 @@ fn blob_get_3_level() {
     assert_eq!(d, b"2356");
 }
 
+#[test]
+fn index_hole_bounds() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    let hole = vec![0u8; s.config().hash.oid_len()];
+    let piece = s.put(vblock::Kind::Piece).unwrap().append(b"ab").unwrap().commit().unwrap();
+
+    // a hole whose end overflows
+    let mut top = vec![];
+    top.extend(&1u64.to_le_bytes());
+    top.extend(&2u64.to_le_bytes());
+    top.extend(piece.as_bytes());
+    top.extend(&u64::max_value().to_le_bytes());
+    top.extend(&hole);
+    let e = s.load_blob(vblock::Kind::Index, &top[..]).err().expect("overflowing hole loaded");
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+
+    // a hole longer than the entry referring to its index
+    let sub = s.put(vblock::Kind::Index).unwrap()
+        .append(&1u64.to_le_bytes()).unwrap()
+        .append(&(1u64 << 40).to_le_bytes()).unwrap()
+        .append(&hole).unwrap()
+        .commit().unwrap();
+    let mut top = vec![];
+    top.extend(&2u64.to_le_bytes());
+    top.extend(&10u64.to_le_bytes());
+    top.extend(sub.as_bytes());
+    let e = s.load_blob(vblock::Kind::Index, &top[..]).err().expect("oversized hole loaded");
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+}
+
 #[test]
 fn blob_round_trip_empty() {
     let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
@@ -235,3 +291,231 @@ fn blob_round_trip() {
     }
     quickcheck::quickcheck(prop as fn(Vec<u8>) -> bool)
 }
+
+#[test]
+fn blob_round_trip_hash_algs() {
+    let data: Vec<u8> = (0..100_000u32).map(|x| (x * 7 % 251) as u8).collect();
+    for h in &[vblock::HashAlg::Sha512, vblock::HashAlg::Sha256, vblock::HashAlg::Blake3] {
+        let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+        let c = vblock::Config { hash: *h, ..vblock::Config::default() };
+        let s = vblock::Store::with_dir_config(Dir::open(tdb.path()).unwrap(), c.clone()).expect("failed to open store");
+        let oid = s.put_blob(&data).expect("put failed");
+        assert_eq!(oid.len(), h.oid_len());
+        let rt_data = s.get_blob(&oid).expect("get failed").expect("object does not exist");
+        assert_eq!(Hs(&data[..]), Hs(&rt_data[..]));
+
+        // reopening picks up the recorded config
+        drop(s);
+        let s = vblock::Store::with_path(tdb.path()).expect("failed to reopen store");
+        assert_eq!(s.config(), &c);
+    }
+}
+
+#[test]
+fn open_baseline_store() {
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let data = b"baseline blob";
+    // the oid vblock gave `data` before stores had a config: sha512 of the kind (Piece) & data
+    let hex = "e5a3f4dfcabb64cbe1bcdc8c72f905449321be6876fbb14bc9167602ce4374e5\
+               bfe995bb24740240b12004d0c58c83f56002377463350fd1bb2cbf919ca363ff";
+
+    // lay the object out as such a store would have: objects under the base, next to an (unused)
+    // `objects` directory
+    ::std::fs::create_dir(tdb.path().join("objects")).unwrap();
+    let mut p = tdb.path().to_path_buf();
+    for i in 0..4 {
+        p.push(&hex[i * 2..i * 2 + 2]);
+    }
+    ::std::fs::create_dir_all(&p).unwrap();
+    let mut o = vec![1u8, 0, 0, 0, 0, 0, 0, 0];
+    o.extend(&data[..]);
+    ::std::fs::write(p.join(&hex[8..]), o).unwrap();
+
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    assert_eq!(s.config().blob_layout, vblock::BlobLayout::Chunked);
+    assert_eq!(s.config().piece_size, vblock::PieceSize::unbounded());
+    let oid = vblock::Oid::from_hex(hex).unwrap();
+    assert_eq!(s.get_blob(&oid).expect("get failed").expect("object does not exist"), &data[..]);
+    assert_eq!(s.put_blob(&data[..]).expect("put failed"), oid);
+
+    // and keeps doing so once the config is recorded
+    drop(s);
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to reopen store");
+    assert_eq!(s.put_blob(&data[..]).expect("put failed"), oid);
+
+    // while a new store gets the defaults
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let s = vblock::Store::with_path(tdb.path()).expect("failed to open store");
+    assert_eq!(s.config(), &vblock::Config::default());
+}
+
+#[test]
+fn blob_round_trip_chunkers() {
+    let data: Vec<u8> = (0..200_000u32).map(|x| (x.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    for k in vblock::ChunkerKind::all() {
+        let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+        let c = vblock::Config { chunker: *k, ..vblock::Config::default() };
+        let s = vblock::Store::with_dir_config(Dir::open(tdb.path()).unwrap(), c).expect("failed to open store");
+        let oid = s.put_blob(&data).expect("put failed");
+        let rt_data = s.get_blob(&oid).expect("get failed").expect("object does not exist");
+        assert_eq!(Hs(&data[..]), Hs(&rt_data[..]));
+    }
+}
+
+#[test]
+fn blob_round_trip_layouts() {
+    // mixed enough that bup (which the unbounded piece size implies) finds boundaries in it
+    let data: Vec<u8> = (0..100_000u64).map(|x| {
+        let z = (x + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        ((z ^ (z >> 31)).wrapping_mul(0xbf58_476d_1ce4_e5b9) >> 56) as u8
+    }).collect();
+    let configs = vec![
+        vblock::Config {
+            blob_layout: vblock::BlobLayout::Chunked,
+            piece_size: vblock::PieceSize::unbounded(),
+            ..vblock::Config::default()
+        },
+        vblock::Config {
+            chunker: vblock::ChunkerKind::Gear,
+            piece_size: vblock::PieceSize { min: 0, avg: 256, max: 256 },
+            blob_layout: vblock::BlobLayout::Tree,
+            fanout: 2,
+            ..vblock::Config::default()
+        },
+    ];
+
+    for c in configs {
+        let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+        let layout = c.blob_layout;
+        let s = vblock::Store::with_dir_config(Dir::open(tdb.path()).unwrap(), c).expect("failed to open store");
+        let oid = s.put_blob(&data).expect("put failed");
+        let top = s.get(&oid).expect("get failed").expect("object does not exist").kind();
+        assert_eq!(top, match layout {
+            vblock::BlobLayout::Chunked => vblock::Kind::Blob,
+            vblock::BlobLayout::Tree | vblock::BlobLayout::CdcTree => vblock::Kind::Index,
+        });
+        let rt_data = s.get_blob(&oid).expect("get failed").expect("object does not exist");
+        assert_eq!(Hs(&data[..]), Hs(&rt_data[..]));
+        s.verify(&oid).expect("verify failed");
+    }
+}
+
+fn count_files(p: &::std::path::Path) -> usize {
+    let mut n = 0;
+    for e in ::std::fs::read_dir(p).unwrap() {
+        let e = e.unwrap();
+        let t = e.file_type().unwrap();
+        if t.is_dir() {
+            n += count_files(&e.path());
+        } else if t.is_file() {
+            n += 1;
+        }
+    }
+    n
+}
+
+#[test]
+fn blob_cdc_tree_edit_locality() {
+    let mut x: u64 = 1;
+    let a: Vec<u8> = (0..200_000).map(|_| {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x as u8
+    }).collect();
+
+    // insert enough data near the start to add pieces, shifting every later entry
+    let mut b = a[..100].to_owned();
+    b.extend((0..5000u32).map(|x| (x * 31) as u8));
+    b.extend(&a[100..]);
+
+    let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+    let c = vblock::Config {
+        chunker: vblock::ChunkerKind::Gear,
+        piece_size: vblock::PieceSize { min: 64, avg: 256, max: 1024 },
+        blob_layout: vblock::BlobLayout::CdcTree,
+        fanout: 8,
+        ..vblock::Config::default()
+    };
+    let s = vblock::Store::with_dir_config(Dir::open(tdb.path()).unwrap(), c).expect("failed to open store");
+    s.put_blob(&a).expect("put failed");
+    let before = count_files(tdb.path());
+    let oid = s.put_blob(&b).expect("put failed");
+    let after = count_files(tdb.path());
+
+    assert!((after - before) * 4 < before, "{} new objects, {} before", after - before, before);
+    let rt_data = s.get_blob(&oid).expect("get failed").expect("object does not exist");
+    assert_eq!(Hs(&b[..]), Hs(&rt_data[..]));
+}
+
+#[test]
+fn blob_threads_same_oid() {
+    let data: Vec<u8> = (0..200_000u32).map(|x| (x.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    let mut oids = vec![];
+    for &threads in &[1, 4] {
+        let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+        let c = vblock::Config {
+            chunker: vblock::ChunkerKind::Gear,
+            piece_size: vblock::PieceSize { min: 64, avg: 256, max: 1024 },
+            ..vblock::Config::default()
+        };
+        let mut s = vblock::Store::with_dir_config(Dir::open(tdb.path()).unwrap(), c).expect("failed to open store");
+        s.set_threads(threads);
+
+        let mut w = s.blob_writer();
+        for part in data.chunks(1000) {
+            w.write_all(part).expect("write failed");
+        }
+        let oid = w.commit().expect("commit failed");
+
+        let rt_data = s.get_blob(&oid).expect("get failed").expect("object does not exist");
+        assert_eq!(Hs(&data[..]), Hs(&rt_data[..]));
+        assert_eq!(oid, s.put_blob(&data).expect("put failed"));
+
+        // holes land between the pieces still being stored around them
+        let mut w = s.blob_writer();
+        let mut sparse = vec![];
+        for (i, part) in data.chunks(1000).enumerate() {
+            w.write_all(part).expect("write failed");
+            sparse.extend_from_slice(part);
+            if i % 20 == 0 {
+                w.write_hole(3000).expect("write_hole failed");
+                sparse.extend(::std::iter::repeat(0u8).take(3000));
+            }
+        }
+        let sparse_oid = w.commit().expect("commit failed");
+        let rt_data = s.get_blob(&sparse_oid).expect("get failed").expect("object does not exist");
+        assert_eq!(Hs(&sparse[..]), Hs(&rt_data[..]));
+
+        oids.push((oid, sparse_oid, s.stored_bytes()));
+    }
+
+    assert_eq!(oids[0], oids[1]);
+}
+
+#[test]
+fn blob_read_ahead() {
+    let data: Vec<u8> = (0..200_000u32).map(|x| (x.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    for &layout in &[vblock::BlobLayout::CdcTree, vblock::BlobLayout::Chunked] {
+        let tdb = tempdir::TempDir::new(module_path!()).expect("failed to open tempdir");
+        let c = vblock::Config {
+            chunker: vblock::ChunkerKind::Gear,
+            piece_size: vblock::PieceSize { min: 64, avg: 256, max: 1024 },
+            blob_layout: layout,
+            fanout: 4,
+            ..vblock::Config::default()
+        };
+        let mut s = vblock::Store::with_dir_config(Dir::open(tdb.path()).unwrap(), c).expect("failed to open store");
+        let oid = s.put_blob(&data).expect("put failed");
+
+        for &(threads, read_ahead) in &[(1, 1), (4, 1), (4, 3), (3, 64)] {
+            s.set_threads(threads);
+            s.set_read_ahead(read_ahead);
+            let mut rt_data = vec![];
+            let len = s.copy_blob(&oid, &mut rt_data).expect("copy failed").expect("object does not exist");
+            assert_eq!(len, data.len() as u64);
+            assert_eq!(Hs(&data[..]), Hs(&rt_data[..]));
+        }
+        s.verify(&oid).expect("verify failed");
+    }
+}